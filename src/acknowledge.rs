@@ -0,0 +1,771 @@
+use chrono::{SecondsFormat, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+
+use crate::atlas;
+use crate::config::Secret;
+use crate::destination;
+use crate::links;
+use crate::metrics;
+use crate::post;
+use crate::server::State;
+
+const REDACTED: &str = "<redacted>";
+
+// Acknowledge buttons lead to a form on this server, which calls the atlas api with the key of the project
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct AckConfig {
+    // Url this server is reached at from the browser of whoever clicks the button
+    pub public_url: String,
+
+    // Key the links in the buttons are signed with
+    pub secret: Secret,
+
+    // The atlas api by default, {url}/api/public/v1.0 for an Ops Manager
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+
+    // Hours an alert can be acknowledged for, the first one is preselected in the form
+    #[serde(default = "default_hours")]
+    pub hours: Vec<u64>,
+
+    // Days a button keeps working after the card was posted
+    #[serde(default = "default_expiry_days")]
+    pub expiry_days: u64,
+
+    // Programmatic api keys by project id, alerts of other projects get no button
+    #[serde(default)]
+    pub projects: BTreeMap<String, ApiKey>,
+}
+
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub public_key: String,
+    pub private_key: Secret,
+}
+
+fn default_hours() -> Vec<u64> {
+    vec![1, 4, 24]
+}
+
+fn default_expiry_days() -> u64 {
+    7
+}
+
+// What a signed link allows, acknowledging one alert and updating the card in one channel
+#[derive(Serialize, Deserialize, Debug)]
+struct Token {
+    group: String,
+    alert: String,
+    channel: String,
+    expires: i64,
+}
+
+impl AckConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let public_url = url::Url::parse(&self.public_url)
+            .map_err(|e| format!("invalid public_url {}: {}", self.public_url, e))?;
+        if !["http", "https"].contains(&public_url.scheme()) {
+            return Err(format!(
+                "public_url {} must be http or https",
+                self.public_url
+            ));
+        }
+        if let Some(api_url) = &self.api_url {
+            url::Url::parse(api_url).map_err(|e| format!("invalid api_url {}: {}", api_url, e))?;
+        }
+        if self.secret.expose().is_empty() {
+            return Err("secret must not be empty".to_string());
+        }
+        if self.hours.is_empty() || self.hours.contains(&0) {
+            return Err("hours must list at least one duration of one hour or more".to_string());
+        }
+        if self.expiry_days == 0 {
+            return Err("expiry_days must be at least one day".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn redacted(&self) -> serde_json::Value {
+        let projects: BTreeMap<&String, serde_json::Value> = self
+            .projects
+            .iter()
+            .map(|(group, key)| {
+                (
+                    group,
+                    json!({"public_key": key.public_key, "private_key": REDACTED}),
+                )
+            })
+            .collect();
+        let mut value = json!({
+            "public_url": self.public_url,
+            "secret": REDACTED,
+            "hours": self.hours,
+            "expiry_days": self.expiry_days,
+            "projects": projects,
+        });
+        if let Some(api_url) = &self.api_url {
+            value["api_url"] = json!(api_url);
+        }
+        value
+    }
+
+    fn api_url(&self) -> &str {
+        self.api_url.as_deref().unwrap_or(atlas::ATLAS_API)
+    }
+
+    // Signed link to the acknowledge form, None for alerts this server can not acknowledge
+    pub fn link(&self, channel: &str, alert: &serde_json::Value) -> Option<String> {
+        if !links::acknowledgeable(alert) || channel.is_empty() {
+            return None;
+        }
+        let group = alert["groupId"].as_str()?;
+        let id = alert["id"].as_str()?;
+        if !self.projects.contains_key(group) {
+            return None;
+        }
+        let token = Token {
+            group: group.to_string(),
+            alert: id.to_string(),
+            channel: channel.to_string(),
+            expires: Utc::now().timestamp() + self.expiry_days as i64 * 86400,
+        };
+        match self.sign(&token) {
+            Ok(token) => Some(format!(
+                "{}/acknowledge?token={}",
+                self.public_url.trim_end_matches('/'),
+                token
+            )),
+            Err(e) => {
+                log::error!("Unable to sign acknowledge link: {}", e);
+                None
+            }
+        }
+    }
+
+    fn mac(&self) -> Result<Hmac<Sha256>, String> {
+        Hmac::<Sha256>::new_varkey(self.secret.expose().as_bytes())
+            .map_err(|e| format!("unable to use acknowledge secret: {}", e))
+    }
+
+    // The token is the json of the claims and its HMAC-SHA256, both base64url encoded
+    fn sign(&self, token: &Token) -> Result<String, String> {
+        let payload = base64::encode_config(
+            serde_json::to_vec(token).map_err(|e| e.to_string())?,
+            base64::URL_SAFE_NO_PAD,
+        );
+        let mut mac = self.mac()?;
+        mac.update(payload.as_bytes());
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    fn verify(&self, token: &str) -> Result<Token, String> {
+        let invalid = || "the link is invalid".to_string();
+        let mut pieces = token.splitn(2, '.');
+        let payload = pieces.next().ok_or_else(invalid)?;
+        let signature = pieces
+            .next()
+            .and_then(|signature| base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or_else(invalid)?;
+        let mut mac = self.mac()?;
+        mac.update(payload.as_bytes());
+        mac.verify(&signature).map_err(|_| invalid())?;
+
+        let token: Token = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(invalid)?;
+        if token.expires < Utc::now().timestamp() {
+            return Err("the link has expired".to_string());
+        }
+        Ok(token)
+    }
+}
+
+// Handle GET and POST /acknowledge. The form is only shown on GET, so link previews and
+// crawlers following the button never acknowledge anything.
+pub async fn handle(
+    req: Request<Body>,
+    state: State,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let config = state
+        .config
+        .lock()
+        .expect("Unable to lock config")
+        .acknowledge
+        .clone();
+    let config = match config {
+        Some(config) => config,
+        None => {
+            return Ok(page(
+                StatusCode::NOT_FOUND,
+                "Not available",
+                "Acknowledging alerts is not configured on this server.",
+            ))
+        }
+    };
+
+    match *req.method() {
+        Method::GET => {
+            let fields = form(req.uri().query().unwrap_or_default().as_bytes());
+            let token = fields.get("token").map(String::as_str).unwrap_or_default();
+            match config.verify(token) {
+                Ok(claims) => Ok(form_page(&config, token, &claims)),
+                Err(e) => {
+                    log::error!("Rejected acknowledge link: {}", e);
+                    Ok(page(StatusCode::BAD_REQUEST, "Unable to acknowledge", &e))
+                }
+            }
+        }
+        Method::POST => {
+            let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+            Ok(submit(&config, &form(&whole_body), &state).await)
+        }
+        _ => {
+            let mut response = Response::default();
+            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            Ok(response)
+        }
+    }
+}
+
+fn form(input: &[u8]) -> BTreeMap<String, String> {
+    url::form_urlencoded::parse(input).into_owned().collect()
+}
+
+async fn submit(
+    config: &AckConfig,
+    fields: &BTreeMap<String, String>,
+    state: &State,
+) -> Response<Body> {
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let checked = config
+        .verify(field("token").unwrap_or_default())
+        .and_then(|claims| {
+            let name = field("name").ok_or("a name is needed")?;
+            let hours = field("hours")
+                .and_then(|hours| hours.parse::<u64>().ok())
+                .filter(|hours| config.hours.contains(hours))
+                .ok_or("the duration is not one of those offered")?;
+            let key = config
+                .projects
+                .get(&claims.group)
+                .ok_or("there is no api key for the project of the alert")?;
+            Ok((claims, name, hours, key))
+        });
+    let (claims, name, hours, key) = match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            log::error!("Rejected acknowledgement: {}", e);
+            metrics::acknowledgement("rejected");
+            return page(StatusCode::BAD_REQUEST, "Unable to acknowledge", &e);
+        }
+    };
+
+    // Names and comments end up in the api and on the card, so keep them short
+    let name: String = name.chars().take(100).collect();
+    let comment = match field("comment") {
+        Some(comment) => format!(
+            "{} (acknowledged by {})",
+            comment.chars().take(200).collect::<String>(),
+            name
+        ),
+        None => format!("Acknowledged by {}", name),
+    };
+    let until = (Utc::now() + chrono::Duration::hours(hours as i64))
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    let credentials = atlas::Credentials {
+        username: &key.public_key,
+        password: key.private_key.expose(),
+    };
+    let acknowledged = atlas::acknowledge_alert(
+        &state.client,
+        config.api_url(),
+        &credentials,
+        &claims.group,
+        &claims.alert,
+        &until,
+        &comment,
+    )
+    .await;
+    let mut alert = match acknowledged {
+        Ok(alert) if alert.is_object() => alert,
+        Ok(_) => {
+            log::error!(
+                "Unable to acknowledge id: {}, api answered without the alert",
+                claims.alert
+            );
+            metrics::acknowledgement("failed");
+            return page(
+                StatusCode::BAD_GATEWAY,
+                "Unable to acknowledge",
+                "The api did not answer with the alert.",
+            );
+        }
+        Err(e) => {
+            log::error!("Unable to acknowledge id: {}: {}", claims.alert, e);
+            metrics::acknowledgement("failed");
+            return page(
+                StatusCode::BAD_GATEWAY,
+                "Unable to acknowledge",
+                &e.to_string(),
+            );
+        }
+    };
+    log::info!(
+        "Acknowledged id: {} in project {} until {} for {}",
+        claims.alert,
+        claims.group,
+        until,
+        name
+    );
+    metrics::acknowledgement("success");
+
+    // The api records the key as the acknowledging user, the card shows who clicked
+    alert["acknowledgingUsername"] = json!(name);
+    let message = if update(state, &claims.channel, &alert).await {
+        format!("The alert is acknowledged until {}.", until)
+    } else {
+        format!(
+            "The alert is acknowledged until {}, but the card could not be updated.",
+            until
+        )
+    };
+    page(StatusCode::OK, "Alert acknowledged", &message)
+}
+
+// Incoming webhooks can not edit a card once it is posted, so the updated card is posted after it
+async fn update(state: &State, channel: &str, alert: &serde_json::Value) -> bool {
+    let entry = state
+        .config
+        .lock()
+        .expect("Unable to lock config")
+        .channel(channel);
+    let entry = match entry {
+        Some(entry) => entry,
+        None => {
+            log::error!(
+                "Channel {} no longer exists, not updating the card",
+                channel
+            );
+            return false;
+        }
+    };
+    let card_body = match destination::render_channel(&entry, alert) {
+        Ok(card_body) => card_body,
+        Err(e) => {
+            log::error!("Unable to render acknowledged id: {}: {}", alert["id"], e);
+            return false;
+        }
+    };
+    destination::send(&entry, &card_body, &state.client, &state.retry).await == post::Delivery::Sent
+}
+
+fn form_page(config: &AckConfig, token: &str, claims: &Token) -> Response<Body> {
+    let options: String = config
+        .hours
+        .iter()
+        .map(|hours| {
+            let unit = if *hours == 1 { "hour" } else { "hours" };
+            format!("<option value=\"{}\">{} {}</option>", hours, hours, unit)
+        })
+        .collect();
+    let body = format!(
+        "<p>Alert {} in project {}</p>\n\
+         <form method=\"post\" action=\"acknowledge\">\n\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\n\
+         <p><label>Your name <input name=\"name\" maxlength=\"100\" required></label></p>\n\
+         <p><label>Acknowledge for <select name=\"hours\">{}</select></label></p>\n\
+         <p><label>Comment <input name=\"comment\" maxlength=\"200\"></label></p>\n\
+         <p><button type=\"submit\">Acknowledge</button></p>\n\
+         </form>",
+        escape(&claims.alert),
+        escape(&claims.group),
+        escape(token),
+        options
+    );
+    html(StatusCode::OK, "Acknowledge alert", &body)
+}
+
+fn page(status: StatusCode, title: &str, message: &str) -> Response<Body> {
+    html(status, title, &format!("<p>{}</p>", escape(message)))
+}
+
+fn html(status: StatusCode, title: &str, body: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n{}\n</body>\n</html>\n",
+        title, title, body
+    )));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    response
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use md5::{Digest, Md5};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use crate::config::Config;
+    use crate::digest;
+    use crate::post;
+
+    const GROUP: &str = "5d2f6c93aa9b4c5735a37474";
+    const ALERT: &str = "5f931f964e5a5d4a6ea7c7a5";
+    const NONCE: &str = "abc123nonce";
+
+    fn config(api_url: &str) -> AckConfig {
+        serde_yaml::from_str(&format!(
+            "public_url: http://alerts.example.com/\n\
+             secret: s3cret\n\
+             api_url: {}\n\
+             projects:\n  {}:\n    public_key: pubkey\n    private_key: privkey\n",
+            api_url, GROUP
+        ))
+        .unwrap()
+    }
+
+    fn alert() -> serde_json::Value {
+        json!({
+            "id": ALERT,
+            "groupId": GROUP,
+            "status": "OPEN",
+            "eventTypeName": "HOST_DOWN",
+            "created": "2020-10-23T09:12:31Z",
+            "updated": "2020-10-23T09:12:31Z",
+        })
+    }
+
+    fn token(config: &AckConfig) -> String {
+        let link = config.link("teams", &alert()).unwrap();
+        link.strip_prefix("http://alerts.example.com/acknowledge?token=")
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn link_round_trips() {
+        let config = config(atlas::ATLAS_API);
+        let claims = config.verify(&token(&config)).unwrap();
+        assert_eq!(claims.group, GROUP);
+        assert_eq!(claims.alert, ALERT);
+        assert_eq!(claims.channel, "teams");
+        let days = (claims.expires - Utc::now().timestamp()) as f64 / 86400.0;
+        assert!(days > 6.9 && days <= 7.0);
+    }
+
+    #[test]
+    fn no_link_for_alerts_that_can_not_be_acknowledged() {
+        let config = config(atlas::ATLAS_API);
+        let mut other = alert();
+        other["groupId"] = json!("000000000000000000000000");
+        assert!(config.link("teams", &other).is_none());
+
+        let mut closed = alert();
+        closed["status"] = json!("CLOSED");
+        assert!(config.link("teams", &closed).is_none());
+
+        let mut acknowledged = alert();
+        acknowledged["acknowledgedUntil"] = json!("2020-10-23T10:12:31Z");
+        assert!(config.link("teams", &acknowledged).is_none());
+
+        assert!(config.link("", &alert()).is_none());
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let config = config(atlas::ATLAS_API);
+        let token = token(&config);
+        let (payload, signature) = token.split_at(token.find('.').unwrap());
+
+        // Claims for another alert, carrying the signature of the original ones
+        let forged = Token {
+            group: GROUP.to_string(),
+            alert: "000000000000000000000000".to_string(),
+            channel: "teams".to_string(),
+            expires: Utc::now().timestamp() + 86400,
+        };
+        let forged = base64::encode_config(
+            serde_json::to_vec(&forged).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let invalid = Err("the link is invalid".to_string());
+        assert_eq!(
+            config
+                .verify(&format!("{}{}", forged, signature))
+                .map(|_| ()),
+            invalid
+        );
+
+        let mut flipped = signature.to_string().into_bytes();
+        let last = flipped.len() - 2;
+        flipped[last] = if flipped[last] == b'A' { b'B' } else { b'A' };
+        let flipped = String::from_utf8(flipped).unwrap();
+        assert_eq!(
+            config
+                .verify(&format!("{}{}", payload, flipped))
+                .map(|_| ()),
+            invalid
+        );
+
+        for token in &["", "abc", "abc.", "abc.!!!", payload] {
+            assert_eq!(config.verify(token).map(|_| ()), invalid);
+        }
+    }
+
+    #[test]
+    fn tokens_of_another_secret_are_rejected() {
+        let token = token(&config(atlas::ATLAS_API));
+        let mut other = config(atlas::ATLAS_API);
+        other.secret = serde_json::from_value(json!("other")).unwrap();
+        assert_eq!(
+            other.verify(&token).map(|_| ()),
+            Err("the link is invalid".to_string())
+        );
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let config = config(atlas::ATLAS_API);
+        let expired = config
+            .sign(&Token {
+                group: GROUP.to_string(),
+                alert: ALERT.to_string(),
+                channel: "teams".to_string(),
+                expires: Utc::now().timestamp() - 1,
+            })
+            .unwrap();
+        assert_eq!(
+            config.verify(&expired).map(|_| ()),
+            Err("the link has expired".to_string())
+        );
+    }
+
+    // What the mock server was sent: method, path, authorization header and body
+    type Requests = Arc<Mutex<Vec<(Method, String, Option<String>, String)>>>;
+
+    fn md5_hex(value: &str) -> String {
+        format!("{:x}", Md5::digest(value.as_bytes()))
+    }
+
+    // Check the digest answer the way atlas would, with the key of the test project
+    fn authorized(authorization: &str, path: &str) -> bool {
+        let params: BTreeMap<&str, &str> = authorization
+            .trim_start_matches("Digest ")
+            .split(", ")
+            .filter_map(|param| {
+                let mut pieces = param.splitn(2, '=');
+                Some((pieces.next()?, pieces.next()?.trim_matches('"')))
+            })
+            .collect();
+        let ha1 = md5_hex("pubkey:MMS Public API:privkey");
+        let ha2 = md5_hex(&format!("PATCH:{}", path));
+        let expected = md5_hex(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1,
+            NONCE,
+            params.get("nc").unwrap_or(&""),
+            params.get("cnonce").unwrap_or(&""),
+            ha2
+        ));
+        params.get("username") == Some(&"pubkey")
+            && params.get("uri") == Some(&path)
+            && params.get("response") == Some(&expected.as_str())
+    }
+
+    // Atlas api under /api/atlas/v1.0 and a webhook under /webhook
+    async fn mock(requests: Requests, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let authorization = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        requests.lock().unwrap().push((
+            method.clone(),
+            path.clone(),
+            authorization.clone(),
+            body.clone(),
+        ));
+
+        let mut response = Response::new(Body::empty());
+        if path == "/webhook" {
+            return Ok(response);
+        }
+        match authorization {
+            Some(authorization) if authorized(&authorization, &path) => {
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                let mut alert = alert();
+                alert["acknowledgedUntil"] = body["acknowledgedUntil"].clone();
+                alert["acknowledgementComment"] = body["acknowledgementComment"].clone();
+                alert["acknowledgingUsername"] = json!("pubkey");
+                *response.body_mut() = Body::from(alert.to_string());
+            }
+            _ => {
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                response.headers_mut().insert(
+                    hyper::header::WWW_AUTHENTICATE,
+                    hyper::header::HeaderValue::from_str(&format!(
+                        "Digest realm=\"MMS Public API\", domain=\"\", nonce=\"{}\", algorithm=MD5, qop=\"auth\", stale=false",
+                        NONCE
+                    ))
+                    .unwrap(),
+                );
+            }
+        }
+        Ok(response)
+    }
+
+    fn serve(requests: Requests) -> SocketAddr {
+        let service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| mock(requests.clone(), req))) }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn state(addr: SocketAddr) -> State {
+        let mut config: Config =
+            serde_yaml::from_str(&format!("teams:\n  url: http://{}/webhook\n", addr)).unwrap();
+        config.acknowledge = Some(self::config(&format!("http://{}/api/atlas/v1.0", addr)));
+        config.validate().unwrap();
+
+        let client = post::HttpsClient::new(&post::ClientOptions::default()).unwrap();
+        let retry = post::RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        State {
+            config: Arc::new(Mutex::new(config)),
+            client: client.clone(),
+            retry: retry.clone(),
+            queue: None,
+            admin: None,
+            dedup: None,
+            digests: digest::Digests::new(client, retry, None, None),
+        }
+    }
+
+    fn post(token: &str, hours: &str) -> Request<Body> {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .append_pair("name", "Jane Doe")
+            .append_pair("hours", hours)
+            .append_pair("comment", "looking into it")
+            .finish();
+        Request::post("/acknowledge")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn text(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn form_is_shown_for_valid_links() {
+        let state = state(serve(Requests::default()));
+        let token = token(&state.config.lock().unwrap().acknowledge.clone().unwrap());
+
+        let uri = format!("/acknowledge?token={}", token);
+        let response = handle(
+            Request::get(uri).body(Body::empty()).unwrap(),
+            state.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(text(response).await.contains(&token));
+
+        let uri = "/acknowledge?token=abc.def";
+        let response = handle(Request::get(uri).body(Body::empty()).unwrap(), state)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn acknowledges_through_digest_auth_and_posts_the_card() {
+        let requests = Requests::default();
+        let state = state(serve(requests.clone()));
+        let token = token(&state.config.lock().unwrap().acknowledge.clone().unwrap());
+
+        let response = handle(post(&token, "4"), state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(text(response)
+            .await
+            .contains("The alert is acknowledged until"));
+
+        let requests = requests.lock().unwrap();
+        let path = format!("/api/atlas/v1.0/groups/{}/alerts/{}", GROUP, ALERT);
+        assert_eq!(requests.len(), 3);
+
+        // Challenged first, then answered with the key of the project
+        assert_eq!(requests[0].0, Method::PATCH);
+        assert_eq!(requests[0].1, path);
+        assert_eq!(requests[0].2, None);
+        assert_eq!(requests[1].1, path);
+        assert!(authorized(requests[1].2.as_deref().unwrap(), &path));
+        let patch: serde_json::Value = serde_json::from_str(&requests[1].3).unwrap();
+        assert_eq!(
+            patch["acknowledgementComment"],
+            "looking into it (acknowledged by Jane Doe)"
+        );
+        let until =
+            chrono::DateTime::parse_from_rfc3339(patch["acknowledgedUntil"].as_str().unwrap())
+                .unwrap();
+        let hours = (until.timestamp() - Utc::now().timestamp()) as f64 / 3600.0;
+        assert!(hours > 3.9 && hours <= 4.0);
+
+        // The updated card names who clicked, not the api key
+        assert_eq!(requests[2].0, Method::POST);
+        assert_eq!(requests[2].1, "/webhook");
+        assert!(requests[2].3.contains("Alert Acknowledged"));
+        assert!(requests[2].3.contains("Jane Doe"));
+        assert!(!requests[2].3.contains("pubkey"));
+    }
+
+    #[tokio::test]
+    async fn durations_not_offered_are_rejected() {
+        let requests = Requests::default();
+        let state = state(serve(requests.clone()));
+        let token = token(&state.config.lock().unwrap().acknowledge.clone().unwrap());
+
+        let response = handle(post(&token, "3"), state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::json;
+use std::str::from_utf8;
+
+use crate::auth;
+use crate::config;
+use crate::server::State;
+
+// Settings for the runtime channel management api
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub token: String,
+
+    // When set, every change is written back to this config file
+    pub persist: Option<String>,
+}
+
+fn respond(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+pub fn authorized(headers: &HeaderMap, admin: &Admin) -> bool {
+    headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| auth::secret_matches(token.trim(), &admin.token))
+        .unwrap_or(false)
+}
+
+// Handle GET /channels and GET/PUT/DELETE /channels/{name}
+pub async fn channels(
+    req: Request<Body>,
+    state: State,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let admin = match &state.admin {
+        Some(admin) => admin.clone(),
+        None => {
+            return Ok(respond(
+                StatusCode::NOT_FOUND,
+                json!({"error": "channel api is disabled, set --admin-token to enable it"}),
+            ))
+        }
+    };
+
+    if !authorized(req.headers(), &admin) {
+        log::error!(
+            "Rejected unauthorized {} {}",
+            req.method(),
+            req.uri().path()
+        );
+        return Ok(respond(
+            StatusCode::UNAUTHORIZED,
+            json!({"error": "missing or invalid bearer token"}),
+        ));
+    }
+
+    let name = req
+        .uri()
+        .path()
+        .trim_start_matches("/channels")
+        .trim_start_matches('/')
+        .to_string();
+
+    match (req.method().clone(), name.is_empty()) {
+        (Method::GET, true) => {
+            let config = state.config.lock().expect("Unable to lock config");
+            let mut names: Vec<&String> = config.channels.keys().collect();
+            names.sort();
+            Ok(respond(StatusCode::OK, json!(names)))
+        }
+
+        (Method::GET, false) => {
+            let config = state.config.lock().expect("Unable to lock config");
+            let (parts, _) = req.into_parts();
+            match config.channels.get(&name) {
+                Some(entry) if config::reveal(&parts) => {
+                    Ok(respond(StatusCode::OK, serde_json::to_value(entry)?))
+                }
+                Some(entry) => Ok(respond(StatusCode::OK, entry.redacted())),
+                None => Ok(respond(
+                    StatusCode::NOT_FOUND,
+                    json!({"error": format!("channel {} not found", name)}),
+                )),
+            }
+        }
+
+        (Method::PUT, false) => {
+            if config::RESERVED.contains(&name.as_str()) {
+                return Ok(respond(
+                    StatusCode::BAD_REQUEST,
+                    json!({"error": format!("{} is a reserved key", name)}),
+                ));
+            }
+
+            let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+            let whole_body_vec = whole_body.iter().cloned().collect::<Vec<u8>>();
+            let value = from_utf8(&whole_body_vec).to_owned()?;
+            let entry: config::ConfigEntry = match serde_json::from_str(value) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    return Ok(respond(
+                        StatusCode::BAD_REQUEST,
+                        json!({"error": format!("invalid channel: {}", e)}),
+                    ))
+                }
+            };
+
+            // Validate a copy, so a bad channel never reaches the running config
+            let mut config = state.config.lock().expect("Unable to lock config");
+            let mut updated = config.clone();
+            let created = updated.channels.insert(name.clone(), entry).is_none();
+            if let Err(e) = updated.validate() {
+                return Ok(respond(StatusCode::BAD_REQUEST, json!({ "error": e })));
+            }
+            if let Some(file) = &admin.persist {
+                config::save(file, &updated)?;
+            }
+            *config = updated;
+
+            let (action, status) = if created {
+                ("created", StatusCode::CREATED)
+            } else {
+                ("updated", StatusCode::OK)
+            };
+            log::info!("Channel {} {} through the api", name, action);
+            Ok(respond(status, json!({ "channel": name })))
+        }
+
+        (Method::DELETE, false) => {
+            let mut config = state.config.lock().expect("Unable to lock config");
+            let mut updated = config.clone();
+            if updated.channels.remove(&name).is_none() {
+                return Ok(respond(
+                    StatusCode::NOT_FOUND,
+                    json!({"error": format!("channel {} not found", name)}),
+                ));
+            }
+            // Routes still pointing at the channel would otherwise fail silently
+            if let Err(e) = updated.validate() {
+                return Ok(respond(StatusCode::BAD_REQUEST, json!({ "error": e })));
+            }
+            if let Some(file) = &admin.persist {
+                config::save(file, &updated)?;
+            }
+            *config = updated;
+
+            log::info!("Channel {} deleted through the api", name);
+            let mut response = Response::default();
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            Ok(response)
+        }
+
+        _ => Ok(respond(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({"error": "method not allowed"}),
+        )),
+    }
+}
@@ -0,0 +1,241 @@
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, StatusCode};
+use md5::{Digest, Md5};
+use rand::Rng;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::str::from_utf8;
+
+use crate::links::encode;
+use crate::post;
+
+// Base url of the atlas admin api, Ops Manager serves the same api under {url}/api/public/v1.0
+pub const ATLAS_API: &str = "https://cloud.mongodb.com/api/atlas/v1.0";
+
+lazy_static::lazy_static! {
+    static ref CHALLENGE: Regex = Regex::new(r#"(\w+)=(?:"([^"]*)"|([^,\s]*))"#).expect("Unable to compile digest challenge regex");
+}
+
+// Programmatic api key of a project, the api only takes http digest authentication
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+// Acknowledge an alert until the given time, returning the alert as the api now has it
+pub async fn acknowledge_alert(
+    client: &post::HttpsClient,
+    api_url: &str,
+    credentials: &Credentials<'_>,
+    group_id: &str,
+    alert_id: &str,
+    until: &str,
+    comment: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!(
+        "{}/groups/{}/alerts/{}",
+        api_url.trim_end_matches('/'),
+        encode(group_id),
+        encode(alert_id)
+    );
+    let body = serde_json::json!({
+        "acknowledgedUntil": until,
+        "acknowledgementComment": comment,
+    })
+    .to_string();
+    patch(client, &url, &body, credentials).await
+}
+
+// Send the request once without credentials to get the challenge, then again answering it
+async fn patch(
+    client: &post::HttpsClient,
+    url: &str,
+    body: &str,
+    credentials: &Credentials<'_>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let request = |authorization: Option<String>| {
+        let mut req = Request::builder()
+            .method("PATCH")
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("Accept", "application/json");
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+        req.body(Body::from(body.to_string()))
+    };
+
+    let response = client.request(request(None)?, None).await?;
+    let response = if response.status() == StatusCode::UNAUTHORIZED {
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Digest "))
+            .ok_or("api did not offer digest authentication")?
+            .to_string();
+        let uri = url::Url::parse(url)?;
+        let authorization = authorization(&challenge, uri.path(), credentials)?;
+        client.request(request(Some(authorization))?, None).await?
+    } else {
+        response
+    };
+
+    let status = response.status();
+    let whole_body = hyper::body::to_bytes(response.into_body()).await?;
+    let value = from_utf8(&whole_body).unwrap_or("Could not convert bytes");
+    if !status.is_success() {
+        return Err(format!("api answered {}: {}", status, value).into());
+    }
+    Ok(serde_json::from_str(value)?)
+}
+
+fn md5_hex(value: &str) -> String {
+    format!("{:x}", Md5::digest(value.as_bytes()))
+}
+
+// Answer a digest challenge as described in RFC 7616, with the MD5 algorithm atlas uses
+fn authorization(
+    challenge: &str,
+    uri: &str,
+    credentials: &Credentials<'_>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let params: BTreeMap<String, String> = CHALLENGE
+        .captures_iter(challenge)
+        .map(|captures| {
+            let value = captures.get(2).or_else(|| captures.get(3));
+            (
+                captures[1].to_lowercase(),
+                value
+                    .map(|value| value.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        })
+        .collect();
+    let realm = params
+        .get("realm")
+        .ok_or("digest challenge without realm")?;
+    let nonce = params
+        .get("nonce")
+        .ok_or("digest challenge without nonce")?;
+    if let Some(algorithm) = params.get("algorithm") {
+        if !algorithm.eq_ignore_ascii_case("MD5") {
+            return Err(format!("unsupported digest algorithm {}", algorithm).into());
+        }
+    }
+
+    let ha1 = md5_hex(&format!(
+        "{}:{}:{}",
+        credentials.username, realm, credentials.password
+    ));
+    let ha2 = md5_hex(&format!("PATCH:{}", uri));
+    let qop = params
+        .get("qop")
+        .map(|qop| qop.split(',').any(|option| option.trim() == "auth"))
+        .unwrap_or(false);
+
+    let mut authorization = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=MD5",
+        credentials.username, realm, nonce, uri
+    );
+    if qop {
+        let cnonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let nc = "00000001";
+        let response = md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2));
+        authorization.push_str(&format!(
+            ", qop=auth, nc={}, cnonce=\"{}\", response=\"{}\"",
+            nc, cnonce, response
+        ));
+    } else {
+        let response = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+        authorization.push_str(&format!(", response=\"{}\"", response));
+    }
+    if let Some(opaque) = params.get("opaque") {
+        authorization.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+    Ok(authorization)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example of RFC 2617, whose HA1 is 939e7578ed9e3c518a452acee763bce9
+    const CREDENTIALS: Credentials<'static> = Credentials {
+        username: "Mufasa",
+        password: "Circle Of Life",
+    };
+    const NONCE: &str = "dcd98b7102dd2f0e8b11d0f600bfb0c093";
+    const URI: &str = "/dir/index.html";
+
+    // md5 of "PATCH:/dir/index.html"
+    const HA2: &str = "83bf562bef66510d10a783fa66d6696a";
+
+    fn params(authorization: &str) -> BTreeMap<String, String> {
+        CHALLENGE
+            .captures_iter(authorization)
+            .map(|captures| {
+                let value = captures.get(2).or_else(|| captures.get(3)).unwrap();
+                (captures[1].to_string(), value.as_str().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn md5_hex_is_lowercase_hex() {
+        assert_eq!(md5_hex(""), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    #[test]
+    fn answers_challenge_without_qop() {
+        let challenge = format!("realm=\"testrealm@host.com\", nonce=\"{}\"", NONCE);
+        let authorization = authorization(&challenge, URI, &CREDENTIALS).unwrap();
+        assert_eq!(
+            authorization,
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", \
+             algorithm=MD5, response=\"a65a2b63ea26ee2f6b6da86d6964f997\""
+        );
+    }
+
+    #[test]
+    fn answers_challenge_with_qop() {
+        let challenge = format!(
+            "realm=\"testrealm@host.com\", qop=\"auth,auth-int\", nonce=\"{}\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+            NONCE
+        );
+        let authorization = authorization(&challenge, URI, &CREDENTIALS).unwrap();
+        assert!(authorization.starts_with("Digest "));
+        let params = params(&authorization);
+        assert_eq!(params["username"], "Mufasa");
+        assert_eq!(params["uri"], URI);
+        assert_eq!(params["qop"], "auth");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["opaque"], "5ccc069c403ebaf9f0171e9517f40e41");
+        assert_eq!(params["cnonce"].len(), 16);
+
+        let expected = md5_hex(&format!(
+            "939e7578ed9e3c518a452acee763bce9:{}:00000001:{}:auth:{}",
+            NONCE, params["cnonce"], HA2
+        ));
+        assert_eq!(params["response"], expected);
+    }
+
+    #[test]
+    fn picks_a_new_cnonce_each_time() {
+        let challenge = format!("realm=\"r\", nonce=\"{}\", qop=auth", NONCE);
+        let first = authorization(&challenge, URI, &CREDENTIALS).unwrap();
+        let second = authorization(&challenge, URI, &CREDENTIALS).unwrap();
+        assert_ne!(params(&first)["cnonce"], params(&second)["cnonce"]);
+    }
+
+    #[test]
+    fn rejects_incomplete_or_unsupported_challenges() {
+        assert!(authorization(&format!("nonce=\"{}\"", NONCE), URI, &CREDENTIALS).is_err());
+        assert!(authorization("realm=\"r\"", URI, &CREDENTIALS).is_err());
+        let sha = format!("realm=\"r\", nonce=\"{}\", algorithm=SHA-256", NONCE);
+        assert!(authorization(&sha, URI, &CREDENTIALS).is_err());
+    }
+}
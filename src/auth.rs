@@ -0,0 +1,235 @@
+use hmac::{Hmac, Mac, NewMac};
+use http::request::Parts;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use std::fmt;
+
+use crate::config;
+
+const REDACTED: &str = "<redacted>";
+
+// Inbound authentication for /alert. When several methods are configured, any one of them is enough.
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct AuthConfig {
+    // Shared secret passed as ?token=
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    // Http basic auth, as supported by ops manager webhooks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub basic: Option<BasicAuth>,
+}
+
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl AuthConfig {
+    pub fn is_empty(&self) -> bool {
+        self.token.is_none() && self.basic.is_none()
+    }
+
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = json!({});
+        if self.token.is_some() {
+            value["token"] = json!(REDACTED);
+        }
+        if let Some(basic) = &self.basic {
+            value["basic"] = json!({"username": basic.username, "password": REDACTED});
+        }
+        value
+    }
+}
+
+// Keep secrets out of logs
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("token", &self.token.as_ref().map(|_| REDACTED))
+            .field(
+                "basic",
+                &self.basic.as_ref().map(|basic| (&basic.username, REDACTED)),
+            )
+            .finish()
+    }
+}
+
+// Compare without returning early, so a secret cannot be guessed one byte at a time
+pub fn secret_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn basic_matches(parts: &Parts, basic: &BasicAuth) -> bool {
+    let credentials = parts
+        .headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| base64::decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok());
+    match credentials {
+        Some(credentials) => secret_matches(
+            &credentials,
+            &format!("{}:{}", basic.username, basic.password),
+        ),
+        None => false,
+    }
+}
+
+// Check an inbound request against the channel's auth settings, returning why it was rejected
+pub fn verify(auth: Option<&AuthConfig>, parts: &Parts) -> Result<(), String> {
+    let auth = match auth {
+        Some(auth) if !auth.is_empty() => auth,
+        _ => return Ok(()),
+    };
+
+    if let Some(token) = &auth.token {
+        if let Some(given) = config::token(parts) {
+            if secret_matches(&given, token) {
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(basic) = &auth.basic {
+        if basic_matches(parts, basic) {
+            return Ok(());
+        }
+    }
+
+    let mut methods = Vec::new();
+    if auth.token.is_some() {
+        methods.push("token");
+    }
+    if auth.basic.is_some() {
+        methods.push("basic");
+    }
+    Err(format!(
+        "missing or invalid credentials, expected {}",
+        methods.join(" or ")
+    ))
+}
+
+// Verify the base64 HMAC-SHA1 of the raw body that atlas sends in X-MMS-Signature
+pub fn verify_signature(secret: &config::Secret, parts: &Parts, body: &[u8]) -> Result<(), String> {
+    let signature = parts
+        .headers
+        .get("X-MMS-Signature")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| "missing X-MMS-Signature header".to_string())?;
+    let signature = base64::decode(signature.trim())
+        .map_err(|_| "X-MMS-Signature is not valid base64".to_string())?;
+
+    let mut mac = Hmac::<Sha1>::new_varkey(secret.expose().as_bytes())
+        .map_err(|e| format!("unable to use webhook secret: {}", e))?;
+    mac.update(body);
+    mac.verify(&signature)
+        .map_err(|_| "X-MMS-Signature does not match the body".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"id":"5f931f964e5a5d4a6ea7c7a5","status":"OPEN"}"#;
+
+    // Base64 HMAC-SHA1 of BODY with the secret "atlas-secret"
+    const SIGNATURE: &str = "dhwlYesO2kY1OWWtDzKDXtmTNn4=";
+
+    fn parts(uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut req = http::Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    fn secret() -> config::Secret {
+        serde_json::from_value(json!("atlas-secret")).unwrap()
+    }
+
+    fn both() -> AuthConfig {
+        AuthConfig {
+            token: Some("s3cret".to_string()),
+            basic: Some(BasicAuth {
+                username: "atlas".to_string(),
+                password: "hunter2".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn secret_matches_only_equal_strings() {
+        assert!(secret_matches("s3cret", "s3cret"));
+        assert!(!secret_matches("s3creT", "s3cret"));
+        assert!(!secret_matches("s3cre", "s3cret"));
+        assert!(!secret_matches("", "s3cret"));
+    }
+
+    #[test]
+    fn signature_of_atlas_body_is_accepted() {
+        let parts = parts("/alert", &[("X-MMS-Signature", SIGNATURE)]);
+        assert_eq!(verify_signature(&secret(), &parts, BODY), Ok(()));
+    }
+
+    #[test]
+    fn signature_of_tampered_body_is_rejected() {
+        let parts = parts("/alert", &[("X-MMS-Signature", SIGNATURE)]);
+        let tampered = br#"{"id":"5f931f964e5a5d4a6ea7c7a5","status":"CLOSED"}"#;
+        assert_eq!(
+            verify_signature(&secret(), &parts, tampered),
+            Err("X-MMS-Signature does not match the body".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_signature_is_rejected() {
+        assert_eq!(
+            verify_signature(&secret(), &parts("/alert", &[]), BODY),
+            Err("missing X-MMS-Signature header".to_string())
+        );
+    }
+
+    #[test]
+    fn signature_that_is_not_base64_is_rejected() {
+        let parts = parts("/alert", &[("X-MMS-Signature", "not base64!")]);
+        assert_eq!(
+            verify_signature(&secret(), &parts, BODY),
+            Err("X-MMS-Signature is not valid base64".to_string())
+        );
+    }
+
+    #[test]
+    fn either_token_or_basic_is_enough() {
+        let auth = both();
+        let basic = format!("Basic {}", base64::encode("atlas:hunter2"));
+        assert!(verify(Some(&auth), &parts("/alert?token=s3cret", &[])).is_ok());
+        assert!(verify(Some(&auth), &parts("/alert", &[("Authorization", &basic)])).is_ok());
+    }
+
+    #[test]
+    fn wrong_token_and_basic_are_rejected() {
+        let auth = both();
+        let basic = format!("Basic {}", base64::encode("atlas:wrong"));
+        let wrong = parts("/alert?token=wrong", &[("Authorization", &basic)]);
+        assert_eq!(
+            verify(Some(&auth), &wrong),
+            Err("missing or invalid credentials, expected token or basic".to_string())
+        );
+        assert!(verify(Some(&auth), &parts("/alert", &[])).is_err());
+    }
+
+    #[test]
+    fn empty_auth_lets_everything_through() {
+        assert!(verify(Some(&AuthConfig::default()), &parts("/alert", &[])).is_ok());
+        assert!(verify(None, &parts("/alert", &[])).is_ok());
+    }
+}
@@ -0,0 +1,220 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+// Text missing from any other locale is taken from this one
+pub const DEFAULT_LOCALE: &str = "en";
+
+// Bundles shipped with the binary, see the catalog directory
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("../catalog/en.yml")),
+    ("de", include_str!("../catalog/de.yml")),
+    ("ja", include_str!("../catalog/ja.yml")),
+];
+
+// Distinct unknown event types remembered, so junk posts cannot grow the list forever
+const MAX_UNKNOWN: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref CATALOGS: RwLock<BTreeMap<String, Catalog>> = RwLock::new(builtin());
+    static ref UNKNOWN: Mutex<BTreeMap<String, Unknown>> = Mutex::new(BTreeMap::new());
+}
+
+// Every piece of text shown on the cards, in one locale
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Catalog {
+    // Locale the entries of a --catalog file belong to, en when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+
+    // Card titles keyed by alert status
+    #[serde(default)]
+    pub titles: BTreeMap<String, String>,
+
+    // Fact labels keyed by the alert field they show
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    // Everything else, with {placeholders}
+    #[serde(default)]
+    pub text: BTreeMap<String, String>,
+
+    // Descriptions keyed by eventTypeName
+    #[serde(default)]
+    pub events: BTreeMap<String, String>,
+}
+
+impl Catalog {
+    // Entries of the other catalog are added, replacing ours where both have one
+    fn extend(&mut self, other: Catalog) {
+        self.titles.extend(other.titles);
+        self.labels.extend(other.labels);
+        self.text.extend(other.text);
+        self.events.extend(other.events);
+    }
+}
+
+// An eventTypeName received without a description in the catalog
+#[derive(Serialize, Debug, Clone)]
+pub struct Unknown {
+    pub count: u64,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+fn builtin() -> BTreeMap<String, Catalog> {
+    BUILTIN
+        .iter()
+        .map(|(locale, source)| {
+            let catalog: Catalog = serde_yaml::from_str(source)
+                .unwrap_or_else(|e| panic!("Unable to parse built in {} catalog: {}", locale, e));
+            (locale.to_string(), catalog)
+        })
+        .collect()
+}
+
+fn parse(file: &str) -> Result<Catalog, Box<dyn std::error::Error + Send + Sync>> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| format!("unable to read {}: {}", file, e))?;
+    serde_yaml::from_str(&contents).map_err(|e| format!("unable to parse {}: {}", file, e).into())
+}
+
+// Replace the catalogs with the built in ones, plus the override files in order
+pub fn load(files: &[String]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut catalogs = builtin();
+    for file in files {
+        let catalog = parse(file)?;
+        let locale = catalog
+            .locale
+            .clone()
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string());
+        catalogs.entry(locale).or_default().extend(catalog);
+    }
+    log::info!(
+        "Loaded catalog for locales {:?}",
+        catalogs.keys().collect::<Vec<&String>>()
+    );
+
+    // Event types described by the new catalog are no longer unknown
+    let described = &catalogs[DEFAULT_LOCALE].events;
+    UNKNOWN
+        .lock()
+        .expect("Unable to lock unknown event types")
+        .retain(|event_type, _| !described.contains_key(event_type));
+    *CATALOGS.write().expect("Unable to lock catalog") = catalogs;
+    Ok(())
+}
+
+// Reload the override files whenever one of them changes, keeping the current catalog on error
+pub async fn watch(files: Vec<String>, interval: Duration) {
+    let modified = |files: &[String]| {
+        files
+            .iter()
+            .map(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok())
+            .collect::<Vec<_>>()
+    };
+    let mut last = modified(&files);
+    loop {
+        tokio::time::delay_for(interval).await;
+        let current = modified(&files);
+        if current.contains(&None) || current == last {
+            continue;
+        }
+        last = current;
+        if let Err(e) = load(&files) {
+            log::error!("Keeping current catalog, unable to reload: {}", e);
+        }
+    }
+}
+
+pub fn is_locale(locale: &str) -> bool {
+    CATALOGS
+        .read()
+        .expect("Unable to lock catalog")
+        .contains_key(locale)
+}
+
+pub fn locales() -> Vec<String> {
+    CATALOGS
+        .read()
+        .expect("Unable to lock catalog")
+        .keys()
+        .cloned()
+        .collect()
+}
+
+// Look up an entry in the locale, falling back to the default locale
+fn lookup(
+    locale: &str,
+    section: fn(&Catalog) -> &BTreeMap<String, String>,
+    key: &str,
+) -> Option<String> {
+    let catalogs = CATALOGS.read().expect("Unable to lock catalog");
+    [locale, DEFAULT_LOCALE]
+        .iter()
+        .filter_map(|locale| catalogs.get(*locale))
+        .find_map(|catalog| section(catalog).get(key))
+        .cloned()
+}
+
+pub fn describe(locale: &str, event_type: &str) -> Option<String> {
+    lookup(locale, |catalog| &catalog.events, event_type)
+}
+
+pub fn title(locale: &str, status: &str) -> Option<String> {
+    lookup(locale, |catalog| &catalog.titles, status)
+}
+
+pub fn label(locale: &str, field: &str) -> String {
+    lookup(locale, |catalog| &catalog.labels, field).unwrap_or_else(|| field.to_string())
+}
+
+// Text with each {name} replaced by its value
+pub fn text(locale: &str, key: &str, values: &[(&str, String)]) -> String {
+    let mut text = lookup(locale, |catalog| &catalog.text, key).unwrap_or_else(|| key.to_string());
+    for (name, value) in values {
+        text = text.replace(&format!("{{{}}}", name), value);
+    }
+    text
+}
+
+// Remember the eventTypeName of a received alert when the catalog has nothing for it
+pub fn observe(alert: &serde_json::Value) {
+    let event_type = match alert["eventTypeName"].as_str() {
+        Some(event_type) => event_type,
+        None => return,
+    };
+    if describe(DEFAULT_LOCALE, event_type).is_some() {
+        return;
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let mut unknown = UNKNOWN.lock().expect("Unable to lock unknown event types");
+    if let Some(seen) = unknown.get_mut(event_type) {
+        seen.count += 1;
+        seen.last_seen = now;
+        return;
+    }
+    if unknown.len() >= MAX_UNKNOWN {
+        return;
+    }
+    log::warn!("No catalog entry for eventTypeName {}", event_type);
+    unknown.insert(
+        event_type.to_string(),
+        Unknown {
+            count: 1,
+            first_seen: now.clone(),
+            last_seen: now,
+        },
+    );
+}
+
+pub fn unknown() -> BTreeMap<String, Unknown> {
+    UNKNOWN
+        .lock()
+        .expect("Unable to lock unknown event types")
+        .clone()
+}
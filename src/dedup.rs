@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Remembers which alerts were recently accepted, so atlas retries and re-fired alerts
+// are only forwarded once per window
+#[derive(Clone)]
+pub struct Dedup {
+    ttl: Duration,
+    seen: Arc<Mutex<Seen>>,
+}
+
+#[derive(Default)]
+struct Seen {
+    // Alerts delivered or queued, by the time they were accepted
+    accepted: HashMap<String, Instant>,
+
+    // Alerts an earlier post is still delivering
+    in_flight: HashSet<String>,
+}
+
+pub enum Check {
+    // Not seen inside the window, the claim records the alert once it is accepted
+    New(Claim),
+
+    // Already accepted inside the window
+    Duplicate,
+
+    // Still being delivered, so it is not known yet whether the alert will be accepted
+    InFlight,
+}
+
+// Marks an alert as in flight until it is accepted, or until the claim is dropped
+// because delivery failed or the post was abandoned
+pub struct Claim {
+    dedup: Dedup,
+    key: Option<String>,
+}
+
+impl Dedup {
+    pub fn new(ttl: Duration) -> Dedup {
+        Dedup {
+            ttl,
+            seen: Arc::new(Mutex::new(Seen::default())),
+        }
+    }
+
+    // Alerts are told apart by id and status, so an alert closing is never mistaken for a repeat
+    // of it opening. The same alert sent to two channels is forwarded to both.
+    fn key(channel: &str, alert: &serde_json::Value) -> Option<String> {
+        let id = alert["id"].as_str()?;
+        let status = alert["status"].as_str().unwrap_or_default();
+        Some(format!("{}/{}/{}", channel, id, status))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Seen> {
+        self.seen.lock().expect("Unable to lock dedup cache")
+    }
+
+    // Look the alert up, claiming it when it was neither accepted nor is in flight
+    pub fn check(&self, channel: &str, alert: &serde_json::Value) -> Check {
+        let key = match Dedup::key(channel, alert) {
+            Some(key) => key,
+            None => {
+                return Check::New(Claim {
+                    dedup: self.clone(),
+                    key: None,
+                })
+            }
+        };
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let mut seen = self.lock();
+        seen.accepted
+            .retain(|_, added| now.duration_since(*added) < ttl);
+        if seen.accepted.contains_key(&key) {
+            return Check::Duplicate;
+        }
+        if !seen.in_flight.insert(key.clone()) {
+            return Check::InFlight;
+        }
+        Check::New(Claim {
+            dedup: self.clone(),
+            key: Some(key),
+        })
+    }
+
+    // Let a later repeat through, for alerts that were accepted but could not be delivered after all
+    pub fn forget(&self, channel: &str, alert: &serde_json::Value) {
+        if let Some(key) = Dedup::key(channel, alert) {
+            self.lock().accepted.remove(&key);
+        }
+    }
+}
+
+impl Claim {
+    // The alert was delivered or queued, repeats inside the window are now duplicates
+    pub fn accept(mut self) {
+        if let Some(key) = self.key.take() {
+            let mut seen = self.dedup.lock();
+            seen.in_flight.remove(&key);
+            seen.accepted.insert(key, Instant::now());
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.dedup.lock().in_flight.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn alert(status: &str) -> serde_json::Value {
+        json!({"id": "5f931f964e5a5d4a6ea7c7a5", "status": status})
+    }
+
+    fn claim(check: Check) -> Claim {
+        match check {
+            Check::New(claim) => claim,
+            Check::Duplicate => panic!("expected a new alert, got a duplicate"),
+            Check::InFlight => panic!("expected a new alert, got one in flight"),
+        }
+    }
+
+    #[test]
+    fn accepted_alerts_are_duplicates() {
+        let dedup = Dedup::new(Duration::from_secs(60));
+        claim(dedup.check("teams", &alert("OPEN"))).accept();
+        assert!(matches!(
+            dedup.check("teams", &alert("OPEN")),
+            Check::Duplicate
+        ));
+
+        // Another status or another channel is a different alert
+        claim(dedup.check("teams", &alert("CLOSED")));
+        claim(dedup.check("slack", &alert("OPEN")));
+    }
+
+    #[test]
+    fn alerts_being_delivered_are_in_flight() {
+        let dedup = Dedup::new(Duration::from_secs(60));
+        let first = claim(dedup.check("teams", &alert("OPEN")));
+        assert!(matches!(
+            dedup.check("teams", &alert("OPEN")),
+            Check::InFlight
+        ));
+        first.accept();
+        assert!(matches!(
+            dedup.check("teams", &alert("OPEN")),
+            Check::Duplicate
+        ));
+    }
+
+    #[test]
+    fn dropped_claims_let_the_retry_through() {
+        let dedup = Dedup::new(Duration::from_secs(60));
+        drop(claim(dedup.check("teams", &alert("OPEN"))));
+        claim(dedup.check("teams", &alert("OPEN")));
+    }
+
+    #[test]
+    fn entries_expire_and_can_be_forgotten() {
+        let dedup = Dedup::new(Duration::from_millis(0));
+        claim(dedup.check("teams", &alert("OPEN"))).accept();
+        claim(dedup.check("teams", &alert("OPEN"))).accept();
+
+        let dedup = Dedup::new(Duration::from_secs(60));
+        claim(dedup.check("teams", &alert("OPEN"))).accept();
+        dedup.forget("teams", &alert("OPEN"));
+        claim(dedup.check("teams", &alert("OPEN")));
+    }
+
+    #[test]
+    fn alerts_without_id_are_never_duplicates() {
+        let dedup = Dedup::new(Duration::from_secs(60));
+        let alert = json!({"status": "OPEN"});
+        claim(dedup.check("teams", &alert)).accept();
+        claim(dedup.check("teams", &alert));
+    }
+}
@@ -0,0 +1,114 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::config::{ConfigEntry, Kind};
+use crate::links::{self, Action};
+use crate::post;
+use crate::theme::Theme;
+use crate::transform;
+
+// Render the payload expected by a destination kind
+pub fn render(
+    kind: &Kind,
+    alert_json: &serde_json::Value,
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    match kind {
+        Kind::Teams => transform::create_card(alert_json.clone(), theme, locale, actions),
+        Kind::TeamsWorkflow => {
+            transform::create_adaptive_card(alert_json.clone(), theme, locale, actions)
+        }
+        Kind::Slack => transform::create_slack_message(alert_json.clone(), theme, locale, actions),
+        Kind::GenericWebhook => Ok(alert_json.clone()),
+    }
+}
+
+// Buttons for an alert posted to a channel, acknowledging goes through this server when it can
+pub fn actions(entry: &ConfigEntry, alert_json: &serde_json::Value) -> Vec<Action> {
+    let locale = entry.locale();
+    let acknowledge = entry
+        .acknowledge
+        .as_ref()
+        .and_then(|acknowledge| acknowledge.link(&entry.name, alert_json));
+    match &entry.links {
+        Some(links) => links.actions(alert_json, locale, acknowledge),
+        None => acknowledge
+            .map(|url| vec![links::acknowledge_action(locale, url)])
+            .unwrap_or_default(),
+    }
+}
+
+// Render the payload for a channel, with its template when it has one
+pub fn render_channel(
+    entry: &ConfigEntry,
+    alert_json: &serde_json::Value,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let theme = entry.theme.clone().unwrap_or_default();
+    let locale = entry.locale();
+    let actions = actions(entry, alert_json);
+    match (&entry.template, &entry.registry) {
+        (Some(name), Some(registry)) => registry.render(name, alert_json, &theme, locale, actions),
+        _ => render(&entry.kind, alert_json, &theme, locale, actions),
+    }
+}
+
+// Render a single payload for the alerts buffered during a digest window
+pub fn render_digest(
+    entry: &ConfigEntry,
+    alerts: &[serde_json::Value],
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    let theme = entry.theme.clone().unwrap_or_default();
+    let locale = entry.locale();
+    // A digest covers several alerts, so its buttons lead to the cluster rather than one alert
+    let actions = match (&entry.links, alerts.first()) {
+        (Some(links), Some(first)) => links.digest_actions(first, locale),
+        _ => Vec::new(),
+    };
+    match entry.kind {
+        Kind::Teams => transform::create_digest_card(alerts, &theme, locale, actions),
+        Kind::TeamsWorkflow => {
+            transform::create_digest_adaptive_card(alerts, &theme, locale, actions)
+        }
+        Kind::Slack => transform::create_digest_slack_message(alerts, &theme, locale, actions),
+        Kind::GenericWebhook => Ok(json!({
+            "alerts": alerts,
+            "flapped": transform::flapped(alerts),
+        })),
+    }
+}
+
+// Send a rendered payload to the channel's destination
+pub async fn send(
+    entry: &ConfigEntry,
+    body: &serde_json::Value,
+    client: &post::HttpsClient,
+    retry: &post::RetryPolicy,
+) -> post::Delivery {
+    match entry.kind {
+        // Teams and slack incoming webhooks do not take any extra headers
+        Kind::Teams | Kind::TeamsWorkflow | Kind::Slack => {
+            post::post_retry(
+                body,
+                entry.url.to_string(),
+                &BTreeMap::new(),
+                entry.proxy.as_ref(),
+                client,
+                retry,
+            )
+            .await
+        }
+        Kind::GenericWebhook => {
+            post::post_retry(
+                body,
+                entry.url.to_string(),
+                &entry.headers,
+                entry.proxy.as_ref(),
+                client,
+                retry,
+            )
+            .await
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::ConfigEntry;
+use crate::dedup;
+use crate::destination;
+use crate::post;
+use crate::queue;
+
+// Alerts buffered for one group, along with the channel they are sent to
+struct Buffer {
+    channel: String,
+    entry: ConfigEntry,
+    alerts: Vec<serde_json::Value>,
+}
+
+// Groups related alerts of channels with a digest window, so a bad night on one replica set
+// turns into one card per window instead of dozens
+#[derive(Clone)]
+pub struct Digests {
+    client: post::HttpsClient,
+    retry: post::RetryPolicy,
+    // Finished windows go through the delivery queue when there is one
+    queue: Option<queue::Queue>,
+    dedup: Option<dedup::Dedup>,
+    buffers: Arc<Mutex<HashMap<String, Buffer>>>,
+}
+
+impl Digests {
+    pub fn new(
+        client: post::HttpsClient,
+        retry: post::RetryPolicy,
+        queue: Option<queue::Queue>,
+        dedup: Option<dedup::Dedup>,
+    ) -> Digests {
+        Digests {
+            client,
+            retry,
+            queue,
+            dedup,
+            buffers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Alerts of the same kind on the same cluster or replica set end up in the same digest.
+    // Cluster names are only unique within a project, every project starts with a Cluster0.
+    fn key(channel: &str, alert: &serde_json::Value) -> String {
+        let field = |key: &str| alert[key].as_str().unwrap_or_default().to_string();
+        format!(
+            "{}/{}/{}/{}/{}",
+            channel,
+            field("groupId"),
+            field("clusterName"),
+            field("replicaSetName"),
+            field("eventTypeName")
+        )
+    }
+
+    // Buffer an alert, the first alert of a group starts its window. Returns the group key.
+    pub fn add(&self, channel: &str, entry: &ConfigEntry, alert: serde_json::Value) -> String {
+        let key = Digests::key(channel, &alert);
+        let window = match &entry.digest {
+            Some(digest) => Duration::from_secs(digest.window),
+            None => Duration::from_secs(0),
+        };
+
+        let mut buffers = self.buffers.lock().expect("Unable to lock digests");
+        match buffers.get_mut(&key) {
+            Some(buffer) => buffer.alerts.push(alert),
+            None => {
+                buffers.insert(
+                    key.clone(),
+                    Buffer {
+                        channel: channel.to_string(),
+                        entry: entry.clone(),
+                        alerts: vec![alert],
+                    },
+                );
+                tokio::spawn(self.clone().flush(key.clone(), window));
+            }
+        }
+        key
+    }
+
+    // Send everything buffered for the group once its window is over
+    async fn flush(self, key: String, window: Duration) {
+        tokio::time::delay_for(window).await;
+        let buffer = match self
+            .buffers
+            .lock()
+            .expect("Unable to lock digests")
+            .remove(&key)
+        {
+            Some(buffer) => buffer,
+            None => return,
+        };
+
+        if let Some(queue) = &self.queue {
+            let queued = if buffer.alerts.len() == 1 {
+                queue.push(&buffer.channel, &buffer.alerts[0]).await
+            } else {
+                queue.push_digest(&buffer.channel, &buffer.alerts).await
+            };
+            match queued {
+                Ok(queue_id) => {
+                    log::info!("Queued digest {} as {}", key, queue_id);
+                    return;
+                }
+                // Still worth sending right away, rather than dropping the window
+                Err(e) => log::error!("Unable to queue digest {}, sending it now: {}", key, e),
+            }
+        }
+
+        // A lone alert is sent as its usual card
+        let rendered = if buffer.alerts.len() == 1 {
+            destination::render_channel(&buffer.entry, &buffer.alerts[0])
+        } else {
+            destination::render_digest(&buffer.entry, &buffer.alerts)
+        };
+        let sent = match rendered {
+            Ok(card_body) => {
+                destination::send(&buffer.entry, &card_body, &self.client, &self.retry).await
+            }
+            Err(e) => {
+                log::error!("Unable to render digest {}: {}", key, e);
+                post::Delivery::Rejected
+            }
+        };
+
+        if sent == post::Delivery::Sent {
+            log::info!(
+                "Sent digest of {} alerts to channel {}",
+                buffer.alerts.len(),
+                buffer.channel
+            );
+            return;
+        }
+        log::error!(
+            "Failed to send digest of {} alerts to channel {}",
+            buffer.alerts.len(),
+            buffer.channel
+        );
+        // Atlas was answered with 202 and will not send these alerts again, so without a queue
+        // they are lost. Forgetting them only keeps an alert that fires again later from being
+        // dropped as a duplicate.
+        if let Some(dedup) = &self.dedup {
+            for alert in &buffer.alerts {
+                dedup.forget(&buffer.channel, alert);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn key_groups_by_project_cluster_and_event() {
+        let alert = |group: &str, event: &str| {
+            json!({
+                "groupId": group,
+                "clusterName": "Cluster0",
+                "replicaSetName": "Cluster0-shard-0",
+                "eventTypeName": event,
+            })
+        };
+        let key = Digests::key("teams", &alert("5d2f6c93aa9b4c5735a37474", "HOST_DOWN"));
+        assert_eq!(
+            key,
+            "teams/5d2f6c93aa9b4c5735a37474/Cluster0/Cluster0-shard-0/HOST_DOWN"
+        );
+        assert_ne!(
+            key,
+            Digests::key("teams", &alert("5f0c1a2b3c4d5e6f7a8b9c0d", "HOST_DOWN"))
+        );
+        assert_ne!(
+            key,
+            Digests::key(
+                "teams",
+                &alert("5d2f6c93aa9b4c5735a37474", "HOST_RECOVERED")
+            )
+        );
+        assert_ne!(
+            key,
+            Digests::key("slack", &alert("5d2f6c93aa9b4c5735a37474", "HOST_DOWN"))
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::catalog;
+use crate::routing;
+
+// Pages of the atlas ui, Ops Manager serves the same paths under its own url
+const ALERTS: &str = "{base_url}/v2/{groupId}#/alerts";
+const CLUSTER: &str = "{base_url}/v2/{groupId}#/clusters/detail/{clusterName}";
+const HOST: &str = "{base_url}/v2/{groupId}#/host/detail/{hostId}";
+
+lazy_static::lazy_static! {
+    static ref PLACEHOLDER: Regex = Regex::new(r"\{([A-Za-z0-9_.]+)\}").expect("Unable to compile placeholder regex");
+}
+
+// Where the buttons on the cards lead, with {placeholders} filled in from the alert
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct LinksConfig {
+    // https://cloud.mongodb.com for atlas, or the url of an Ops Manager
+    pub base_url: String,
+
+    // Name shown on the open button, Atlas by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+
+    // Page of the alert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert: Option<String>,
+
+    // Metrics of the cluster, or of the host for alerts without a cluster
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    // Page where open alerts are acknowledged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acknowledge: Option<String>,
+}
+
+// A button on a card
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Action {
+    pub title: String,
+    pub url: String,
+}
+
+impl LinksConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let base_url = url::Url::parse(&self.base_url)
+            .map_err(|e| format!("invalid base_url {}: {}", self.base_url, e))?;
+        if !["http", "https"].contains(&base_url.scheme()) {
+            return Err(format!("base_url {} must be http or https", self.base_url));
+        }
+        Ok(())
+    }
+
+    // Fill in a link, or None when the alert lacks one of the fields it needs
+    fn link(&self, template: &str, alert: &serde_json::Value) -> Option<String> {
+        let base_url = self.base_url.trim_end_matches('/');
+        let mut missing = false;
+        let link = PLACEHOLDER.replace_all(template, |captures: &Captures| {
+            let value = match &captures[1] {
+                "base_url" => Some(base_url.to_string()),
+                field => routing::field_value(alert, field).map(|value| encode(&value)),
+            };
+            value.unwrap_or_else(|| {
+                missing = true;
+                String::new()
+            })
+        });
+        if missing {
+            None
+        } else {
+            Some(link.into_owned())
+        }
+    }
+
+    // Buttons for a single alert. The acknowledge button goes to the page of the alert,
+    // unless a signed link back to this server is given.
+    pub fn actions(
+        &self,
+        alert: &serde_json::Value,
+        locale: &str,
+        acknowledge: Option<String>,
+    ) -> Vec<Action> {
+        let mut actions = self.digest_actions(alert, locale);
+        if acknowledgeable(alert) {
+            let template = self.acknowledge.as_deref().unwrap_or(ALERTS);
+            if let Some(url) = acknowledge.or_else(|| self.link(template, alert)) {
+                actions.push(acknowledge_action(locale, url));
+            }
+        }
+        actions
+    }
+
+    // Buttons for a digest card, which covers several alert ids
+    pub fn digest_actions(&self, alert: &serde_json::Value, locale: &str) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let product = self.product.clone().unwrap_or_else(|| "Atlas".to_string());
+        if let Some(url) = self.link(self.alert.as_deref().unwrap_or(ALERTS), alert) {
+            actions.push(Action {
+                title: catalog::text(locale, "action_open", &[("product", product)]),
+                url,
+            });
+        }
+        let metrics = self
+            .link(self.cluster.as_deref().unwrap_or(CLUSTER), alert)
+            .or_else(|| self.link(self.host.as_deref().unwrap_or(HOST), alert));
+        if let Some(url) = metrics {
+            actions.push(Action {
+                title: catalog::text(locale, "action_metrics", &[]),
+                url,
+            });
+        }
+        actions
+    }
+}
+
+// Acknowledging only makes sense while an alert is open and nobody has acknowledged it yet
+pub fn acknowledgeable(alert: &serde_json::Value) -> bool {
+    alert["status"] == "OPEN" && !alert["acknowledgedUntil"].is_string()
+}
+
+pub fn acknowledge_action(locale: &str, url: String) -> Action {
+    Action {
+        title: catalog::text(locale, "action_acknowledge", &[]),
+        url,
+    }
+}
+
+// Percent encode everything but the unreserved characters, so values are safe anywhere in a url
+pub fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
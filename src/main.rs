@@ -5,10 +5,26 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Server};
 use log::LevelFilter;
 use std::io::Write;
+use std::time::Duration;
 
+mod acknowledge;
+mod admin;
+mod atlas;
+mod auth;
+mod catalog;
 mod config;
+mod dedup;
+mod destination;
+mod digest;
+mod links;
+mod metrics;
 mod post;
+mod queue;
+mod routing;
 mod server;
+mod template;
+mod theme;
+mod tls;
 mod transform;
 
 #[tokio::main]
@@ -28,6 +44,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .help("Config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("catalog")
+                .long("catalog")
+                .value_name("FILE")
+                .help("Yaml file of card text for one locale, added on top of the built in catalog, can be repeated")
+                .required(false)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("port")
                 .short("p")
@@ -37,6 +63,174 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .default_value("8000")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .value_name("FILE")
+                .help("PEM certificate chain, serves https when set along with --tls-key")
+                .required(false)
+                .requires("tls_key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("tls-key")
+                .value_name("FILE")
+                .help("PEM private key for --tls-cert")
+                .required(false)
+                .requires("tls_cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_client_ca")
+                .long("tls-client-ca")
+                .value_name("FILE")
+                .help(
+                    "PEM CA certificates, clients must present a certificate signed by one of them",
+                )
+                .required(false)
+                .requires("tls_cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .env("ADMIN_TOKEN")
+                .value_name("TOKEN")
+                .help("Bearer token for the /channels api, which is disabled without one")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("persist_config")
+                .long("persist-config")
+                .help("Write changes made through the /channels api back to the config file")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("reload_interval")
+                .long("reload-interval")
+                .help("Seconds between checks of the config file for changes, 0 to disable")
+                .required(false)
+                .default_value("5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_attempts")
+                .long("retry-attempts")
+                .help("Maximum number of attempts for each post")
+                .required(false)
+                .default_value("3")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_base_delay")
+                .long("retry-base-delay")
+                .help("Delay in milliseconds before the first retry, doubled on each attempt")
+                .required(false)
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_max_delay")
+                .long("retry-max-delay")
+                .help("Maximum delay in milliseconds between retries")
+                .required(false)
+                .default_value("60000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_jitter")
+                .long("retry-jitter")
+                .help("Maximum random jitter in milliseconds added to each retry delay")
+                .required(false)
+                .default_value("1000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .help("Timeout in milliseconds for connecting to a webhook")
+                .required(false)
+                .default_value("10000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("request_timeout")
+                .long("request-timeout")
+                .help("Timeout in milliseconds for each post to a webhook")
+                .required(false)
+                .default_value("30000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pool_max_idle")
+                .long("pool-max-idle")
+                .help("Maximum idle connections kept open per webhook host")
+                .required(false)
+                .default_value("8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .help("Proxy for outbound posts, defaults to HTTPS_PROXY")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no_proxy")
+                .long("no-proxy")
+                .value_name("HOSTS")
+                .help("Comma separated hosts reached without the proxy, defaults to NO_PROXY")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ack_mode")
+                .long("ack-mode")
+                .help("Acknowledge alerts once queued (async), or only once delivered (sync), async when --data-dir is set")
+                .required(false)
+                .possible_values(&["async", "sync"])
+                .default_value("sync")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data_dir")
+                .short("d")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory for the persistent delivery queue")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue_max_rounds")
+                .long("queue-max-rounds")
+                .help("Delivery rounds before a queued alert is moved to failed")
+                .required(false)
+                .default_value("50")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue_concurrency")
+                .long("queue-concurrency")
+                .help("Queued alerts delivered at the same time")
+                .required(false)
+                .default_value("4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dedup_ttl")
+                .long("dedup-ttl")
+                .value_name("SECONDS")
+                .help("Drop alerts with an id and status already seen within this many seconds, 0 to disable")
+                .required(false)
+                .default_value("0")
+                .takes_value(true),
+        )
         .get_matches();
 
     // Initialize log Builder
@@ -55,8 +249,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .parse_default_env()
         .init();
 
+    // Card text, extended by the catalog files when given. Loaded first, since channels
+    // are checked against the known locales.
+    let catalog_files: Vec<String> = opts
+        .values_of("catalog")
+        .map(|files| files.map(String::from).collect())
+        .unwrap_or_default();
+    catalog::load(&catalog_files)?;
+
     // Read in config file
-    let config = config::parse(opts.value_of("config").unwrap())?;
+    let config_file = opts.value_of("config").unwrap().to_string();
+    let config = config::parse(&config_file)?;
+
+    // Pick up changes to the config file without a restart
+    let reload_interval: u64 = opts
+        .value_of("reload_interval")
+        .unwrap()
+        .parse()
+        .unwrap_or(5);
+    if reload_interval > 0 {
+        tokio::spawn(config::watch(
+            config_file.clone(),
+            config.clone(),
+            Duration::from_secs(reload_interval),
+        ));
+    }
+    if reload_interval > 0 && !catalog_files.is_empty() {
+        tokio::spawn(catalog::watch(
+            catalog_files,
+            Duration::from_secs(reload_interval),
+        ));
+    }
+    #[cfg(unix)]
+    tokio::spawn(config::reload_on_hangup(
+        config_file.clone(),
+        config.clone(),
+    ));
+
+    let admin = opts.value_of("admin_token").map(|token| admin::Admin {
+        token: token.to_string(),
+        persist: if opts.is_present("persist_config") {
+            Some(config_file.clone())
+        } else {
+            None
+        },
+    });
     let port: u16 = opts.value_of("port").unwrap().parse().unwrap_or_else(|_| {
         eprintln!("specified port isn't in a valid range, setting to 8080");
         8080
@@ -64,11 +301,124 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let addr = ([0, 0, 0, 0], port).into();
 
+    let defaults = post::RetryPolicy::default();
+    let retry = post::RetryPolicy {
+        max_attempts: opts
+            .value_of("retry_attempts")
+            .unwrap()
+            .parse()
+            .unwrap_or(defaults.max_attempts),
+        base_delay: millis(opts.value_of("retry_base_delay")).unwrap_or(defaults.base_delay),
+        max_delay: millis(opts.value_of("retry_max_delay")).unwrap_or(defaults.max_delay),
+        jitter: millis(opts.value_of("retry_jitter")).unwrap_or(defaults.jitter),
+    };
+
+    // Create the pooled client shared by every delivery
+    let client_defaults = post::ClientOptions::default();
+    let proxy = post::default_proxy(opts.value_of("proxy"), opts.value_of("no_proxy"));
+    let client = post::HttpsClient::new(&post::ClientOptions {
+        connect_timeout: millis(opts.value_of("connect_timeout"))
+            .unwrap_or(client_defaults.connect_timeout),
+        request_timeout: millis(opts.value_of("request_timeout"))
+            .unwrap_or(client_defaults.request_timeout),
+        pool_max_idle: opts
+            .value_of("pool_max_idle")
+            .unwrap()
+            .parse()
+            .unwrap_or(client_defaults.pool_max_idle),
+        proxy,
+    })?;
+
+    // Start the delivery worker unless alerts are posted synchronously
+    // A data dir opts into the queue, as it did before --ack-mode existed
+    let ack_mode = match opts.value_of("ack_mode") {
+        Some(mode) if opts.occurrences_of("ack_mode") > 0 => mode,
+        _ if opts.is_present("data_dir") => "async",
+        _ => "sync",
+    };
+    let queue = match ack_mode {
+        "async" => {
+            let max_rounds = opts
+                .value_of("queue_max_rounds")
+                .unwrap()
+                .parse()
+                .unwrap_or(50);
+            // Atlas stops retrying once an alert is acknowledged, so without a data dir a restart loses it
+            if !opts.is_present("data_dir") {
+                log::error!(
+                    "--ack-mode async without --data-dir keeps queued alerts in memory only, they are lost on restart"
+                );
+            }
+            let concurrency = opts
+                .value_of("queue_concurrency")
+                .unwrap()
+                .parse()
+                .unwrap_or(4);
+            let (queue, receiver) =
+                queue::Queue::open(opts.value_of("data_dir"), max_rounds, concurrency)?;
+            tokio::spawn(queue::run(
+                queue.clone(),
+                receiver,
+                config.clone(),
+                client.clone(),
+                retry.clone(),
+            ));
+            Some(queue)
+        }
+        _ => {
+            if opts.is_present("data_dir") {
+                log::warn!("--data-dir is ignored when --ack-mode is sync");
+            }
+            None
+        }
+    };
+
+    let dedup_ttl: u64 = opts.value_of("dedup_ttl").unwrap().parse().unwrap_or(0);
+    let dedup = if dedup_ttl > 0 {
+        Some(dedup::Dedup::new(Duration::from_secs(dedup_ttl)))
+    } else {
+        None
+    };
+
+    let digests = digest::Digests::new(client.clone(), retry.clone(), queue.clone(), dedup.clone());
+
+    let state = server::State {
+        config,
+        client,
+        retry,
+        queue,
+        admin,
+        dedup,
+        digests,
+    };
+
+    // Terminate https ourselves when a certificate is configured
+    if let (Some(cert), Some(key)) = (opts.value_of("tls_cert"), opts.value_of("tls_key")) {
+        let options = tls::TlsOptions {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            client_ca: opts.value_of("tls_client_ca").map(String::from),
+            reload_interval: Duration::from_secs(reload_interval),
+        };
+
+        println!(
+            "Starting mongo_alerts_2teams:{} on https://{}",
+            crate_version!(),
+            addr
+        );
+
+        if let Err(e) = tls::serve(addr, options, state).await {
+            eprintln!("server error: {}", e);
+        }
+
+        return Ok(());
+    }
+
     let service = make_service_fn(move |_| {
-        let config = config.clone();
+        let state = state.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
-                server::echo(req, config.clone())
+                server::echo(req, state.clone())
             }))
         }
     });
@@ -87,3 +437,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     Ok(())
 }
+
+fn millis(value: Option<&str>) -> Option<Duration> {
+    value?.parse().ok().map(Duration::from_millis)
+}
@@ -0,0 +1,143 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::catalog;
+use crate::post;
+use crate::theme;
+
+const NAMESPACE: &str = "mongo_alerts_2teams";
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+
+    static ref ALERTS_RECEIVED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("alerts_received_total", "Alerts received on /alert")
+            .namespace(NAMESPACE),
+        &["channel", "status", "event_type"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref DELIVERIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("deliveries_total", "Outcome of each delivery, after any retries")
+            .namespace(NAMESPACE),
+        &["outcome"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref DUPLICATES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duplicates_total", "Alerts dropped as duplicates inside the dedup window")
+            .namespace(NAMESPACE),
+        &["channel"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref FILTERED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("alerts_filtered_total", "Alerts dropped by the filter of a channel")
+            .namespace(NAMESPACE),
+        &["channel"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref RETRIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("delivery_retries_total", "Posts retried, by the reason for the retry")
+            .namespace(NAMESPACE),
+        &["reason"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref ACKNOWLEDGEMENTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("acknowledgements_total", "Alerts acknowledged from a card button, by outcome")
+            .namespace(NAMESPACE),
+        &["outcome"],
+    )
+    .expect("Unable to create metric"));
+
+    static ref DELIVERY_SECONDS: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("delivery_duration_seconds", "Time taken by each delivery, including retries")
+            .namespace(NAMESPACE)
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]),
+    )
+    .expect("Unable to create metric"));
+
+    static ref CHANNELS: IntGauge = register(IntGauge::with_opts(
+        Opts::new("channels_configured", "Channels in the running config")
+            .namespace(NAMESPACE),
+    )
+    .expect("Unable to create metric"));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("Unable to register metric");
+    metric
+}
+
+// Alerts that reached a configured channel, labelled with the fields atlas sends. Only known
+// statuses and event types are used as labels, anything else could grow without bound.
+pub fn alert_received(channel: &str, alert: &serde_json::Value) {
+    let status = alert["status"]
+        .as_str()
+        .filter(|status| theme::STATUSES.contains(status))
+        .unwrap_or("unknown");
+    let event_type = alert["eventTypeName"]
+        .as_str()
+        .filter(|event_type| catalog::describe(catalog::DEFAULT_LOCALE, event_type).is_some())
+        .unwrap_or("unknown");
+    ALERTS_RECEIVED
+        .with_label_values(&[channel, status, event_type])
+        .inc();
+}
+
+// Record the result of post_retry, along with how long it took
+pub fn delivery(outcome: post::Delivery, seconds: f64) {
+    let outcome = match outcome {
+        post::Delivery::Sent => "success",
+        post::Delivery::RateLimited => "rate_limited",
+        post::Delivery::Failed => "failed",
+        post::Delivery::Rejected => "rejected",
+    };
+    DELIVERIES.with_label_values(&[outcome]).inc();
+    DELIVERY_SECONDS.observe(seconds);
+}
+
+pub fn duplicate(channel: &str) {
+    DUPLICATES.with_label_values(&[channel]).inc();
+}
+
+pub fn filtered(channel: &str) {
+    FILTERED.with_label_values(&[channel]).inc();
+}
+
+pub fn retry(reason: &str) {
+    RETRIES.with_label_values(&[reason]).inc();
+}
+
+pub fn acknowledgement(outcome: &str) {
+    ACKNOWLEDGEMENTS.with_label_values(&[outcome]).inc();
+}
+
+// Everything in the prometheus text format, served at /metrics
+pub fn gather(channels: usize) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    CHANNELS.set(channels as i64);
+
+    // Make sure every metric shows up, even before anything was delivered
+    for outcome in &["success", "rate_limited", "failed"] {
+        DELIVERIES.with_label_values(&[outcome]);
+    }
+    lazy_static::initialize(&DELIVERY_SECONDS);
+    lazy_static::initialize(&ALERTS_RECEIVED);
+    lazy_static::initialize(&DUPLICATES);
+    lazy_static::initialize(&FILTERED);
+    lazy_static::initialize(&RETRIES);
+    lazy_static::initialize(&ACKNOWLEDGEMENTS);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+pub fn content_type() -> String {
+    TextEncoder::new().format_type().to_string()
+}
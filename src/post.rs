@@ -1,56 +1,527 @@
 use bytes::Bytes;
-use hyper::{Body, Client, Request};
+use chrono::{DateTime, Utc};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, PROXY_AUTHORIZATION};
+use hyper::{Body, Client, Request, Response, StatusCode};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use hyper_tls::HttpsConnector;
+use rand::Rng;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::str::from_utf8;
-use std::{thread, time};
-
-pub async fn post_retry(card_body: &serde_json::Value, url: String) -> Option<bool> {
-    for i in 1..4u16 {
-        if i == 4 {
-            return Some(false);
-        } else {
-            let https = HttpsConnector::new();
-            let client = Client::builder().build::<_, hyper::Body>(https);
-            let req = Request::builder()
-                .method("POST")
-                .uri(url.clone())
-                .header("Content-Type", "application/json")
-                .body(Body::from(card_body.to_string()))
-                .expect("request builder");
-
-            match client.request(req).await {
-                Ok(m) => {
-                    if m.status().as_u16() == 429u16 {
-                        if i == 1 {
-                            log::info!("Detected rate limiting, retrying in 10 seconds");
-                            thread::sleep(time::Duration::from_millis(10000));
-                            continue;
-                        } else if i > 1 && i <= 3 {
-                            log::info!("Detected rate limiting, retrying in 30 seconds");
-                            thread::sleep(time::Duration::from_millis(30000));
-                            continue;
-                        }
-                    } else if m.status().as_u16() == 200u16 {
-                        return Some(true);
-                    } else {
-                        let whole_body = match hyper::body::to_bytes(m.into_body()).await.ok() {
-                            Some(body) => body,
-                            None => Bytes::from("Could not unpack body"),
-                        };
-                        let whole_body_vec = whole_body.iter().cloned().collect::<Vec<u8>>();
-                        let value = from_utf8(&whole_body_vec)
-                            .to_owned()
-                            .expect("Could not convert bytes");
-                        log::info!("Failed to post teams, got error: \"{}\"", value);
-                        return None;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{redact_url, ProxyConfig};
+use crate::metrics;
+
+pub type Connector = ProxyConnector<HttpsConnector<HttpConnector>>;
+
+// Client along with its connector, which knows the proxy headers plain http requests need
+type ProxiedClient = (Client<Connector>, Connector);
+
+// Settings for the outbound client shared by every delivery
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub pool_max_idle: usize,
+    pub proxy: Option<ProxyConfig>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_millis(10000),
+            request_timeout: Duration::from_millis(30000),
+            pool_max_idle: 8,
+            proxy: None,
+        }
+    }
+}
+
+// Pooled https clients, created once at startup and cloned into every request.
+// Channels behind a different proxy get their own client, built the first time it is needed.
+#[derive(Clone)]
+pub struct HttpsClient {
+    options: ClientOptions,
+    clients: Arc<Mutex<HashMap<Option<ProxyConfig>, ProxiedClient>>>,
+}
+
+impl HttpsClient {
+    pub fn new(
+        options: &ClientOptions,
+    ) -> Result<HttpsClient, Box<dyn std::error::Error + Send + Sync>> {
+        let client = HttpsClient {
+            options: options.clone(),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        };
+        // Build the default client right away, so a bad proxy is caught at startup
+        client.client(None)?;
+        Ok(client)
+    }
+
+    fn client(
+        &self,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<ProxiedClient, Box<dyn std::error::Error + Send + Sync>> {
+        let proxy = proxy.or(self.options.proxy.as_ref()).cloned();
+        let mut clients = self.clients.lock().expect("Unable to lock clients");
+        if let Some(client) = clients.get(&proxy) {
+            return Ok(client.clone());
+        }
+
+        let connector = connector(&self.options, proxy.as_ref())?;
+        let client = Client::builder()
+            .pool_max_idle_per_host(self.options.pool_max_idle)
+            .build(connector.clone());
+        clients.insert(proxy, (client.clone(), connector.clone()));
+        Ok((client, connector))
+    }
+
+    // Send a request through the given proxy, giving up once the request timeout is reached
+    pub async fn request(
+        &self,
+        req: Request<Body>,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+        let (client, connector) = self.client(proxy)?;
+
+        // Plain http requests carry the proxy headers themselves, https ones send them with CONNECT
+        let mut req = req;
+        if let Some(headers) = connector.http_headers(req.uri()) {
+            req.headers_mut().extend(headers.clone());
+        }
+
+        let request_timeout = self.options.request_timeout;
+        match tokio::time::timeout(request_timeout, client.request(req)).await {
+            Ok(response) => Ok(response?),
+            Err(_) => Err(format!(
+                "request timed out after {} milliseconds",
+                request_timeout.as_millis()
+            )
+            .into()),
+        }
+    }
+}
+
+fn connector(
+    options: &ClientOptions,
+    proxy: Option<&ProxyConfig>,
+) -> Result<Connector, Box<dyn std::error::Error + Send + Sync>> {
+    let mut http = HttpConnector::new();
+    http.set_connect_timeout(Some(options.connect_timeout));
+    http.enforce_http(false);
+    let tls = native_tls::TlsConnector::new()?;
+    let https = HttpsConnector::from((http, tls.into()));
+
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok(ProxyConnector::new(https)?),
+    };
+
+    // Credentials can be set in the config, or embedded in the proxy url
+    let mut url = url::Url::parse(&proxy.url)?;
+    let mut username = proxy.username.clone();
+    let mut password = proxy.password.clone();
+    if username.is_none() && !url.username().is_empty() {
+        username = Some(url.username().to_string());
+        password = url.password().map(String::from);
+    }
+    url.set_username("").ok();
+    url.set_password(None).ok();
+
+    let no_proxy = proxy.no_proxy.clone();
+    let intercept = Intercept::from(move |_: Option<&str>, host: Option<&str>, _: Option<u16>| {
+        !bypass_proxy(host.unwrap_or_default(), &no_proxy)
+    });
+    let mut hyper_proxy = Proxy::new(intercept, url.as_str().parse()?);
+
+    // Only send Proxy-Authorization, so credentials never reach the webhook itself
+    if let Some(username) = username {
+        let credentials = base64::encode(format!("{}:{}", username, password.unwrap_or_default()));
+        hyper_proxy.set_header(
+            PROXY_AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", credentials))?,
+        );
+    }
+
+    Ok(ProxyConnector::from_proxy(https, hyper_proxy)?)
+}
+
+// Proxy for channels without one in the config. The url and the hosts reached directly are
+// each taken from the command line first, then from HTTPS_PROXY and NO_PROXY.
+pub fn default_proxy(url: Option<&str>, no_proxy: Option<&str>) -> Option<ProxyConfig> {
+    resolve_proxy(url, no_proxy, |name| env::var(name).ok())
+}
+
+fn resolve_proxy(
+    url: Option<&str>,
+    no_proxy: Option<&str>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Option<ProxyConfig> {
+    let from_env = |upper: &str, lower: &str| env(upper).or_else(|| env(lower));
+    let url = url
+        .map(String::from)
+        .or_else(|| from_env("HTTPS_PROXY", "https_proxy"))
+        .filter(|proxy| !proxy.is_empty())?;
+    let no_proxy = no_proxy
+        .map(String::from)
+        .or_else(|| from_env("NO_PROXY", "no_proxy"))
+        .map(|no_proxy| split_no_proxy(&no_proxy))
+        .unwrap_or_default();
+    Some(ProxyConfig {
+        url,
+        username: None,
+        password: None,
+        no_proxy,
+    })
+}
+
+pub fn split_no_proxy(no_proxy: &str) -> Vec<String> {
+    no_proxy
+        .split(',')
+        .map(|host| host.trim().to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+// Hosts listed in no_proxy, and their subdomains, are reached directly
+fn bypass_proxy(host: &str, no_proxy: &[String]) -> bool {
+    let host = host.to_lowercase();
+    no_proxy.iter().any(|entry| {
+        let entry = entry.split(':').next().unwrap_or_default();
+        let domain = entry.trim_start_matches('.');
+        entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+// Controls how many times, and how far apart, a post is attempted
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10000),
+            max_delay: Duration::from_millis(60000),
+            jitter: Duration::from_millis(1000),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff for the given attempt, capped at max_delay, plus random jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = min(
+            self.base_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        );
+        delay + self.jitter()
+    }
+
+    fn jitter(&self) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        if jitter == 0 {
+            return Duration::from_millis(0);
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=jitter))
+    }
+}
+
+// Parse the Retry-After header, which is either a number of seconds or an http date
+fn retry_after(response: &Response<Body>) -> Option<Duration> {
+    let value = response.headers().get("Retry-After")?.to_str().ok()?;
+    match value.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+            let wait = date.with_timezone(&Utc) - Utc::now();
+            Some(wait.to_std().unwrap_or_else(|_| Duration::from_secs(0)))
+        }
+    }
+}
+
+// Outcome of post_retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+
+    // Still rate limited after every attempt
+    RateLimited,
+
+    // Connection and server errors that outlasted the attempts, worth trying again later
+    Failed,
+
+    // Turned down by the webhook, as with a removed webhook or a malformed payload.
+    // Sending the same payload again would only be turned down again.
+    Rejected,
+}
+
+// Only timeouts and server errors may go away, other answers are final
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// Post with retries, telling apart failures that a later round could still get through
+pub async fn post_retry(
+    card_body: &serde_json::Value,
+    url: String,
+    headers: &BTreeMap<String, String>,
+    proxy: Option<&ProxyConfig>,
+    client: &HttpsClient,
+    retry: &RetryPolicy,
+) -> Delivery {
+    let started = Instant::now();
+    let outcome = post_attempts(card_body, url, headers, proxy, client, retry).await;
+    metrics::delivery(outcome, started.elapsed().as_secs_f64());
+    outcome
+}
+
+async fn post_attempts(
+    card_body: &serde_json::Value,
+    url: String,
+    headers: &BTreeMap<String, String>,
+    proxy: Option<&ProxyConfig>,
+    client: &HttpsClient,
+    retry: &RetryPolicy,
+) -> Delivery {
+    let max_attempts = retry.max_attempts.max(1);
+    for i in 1..=max_attempts {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri(url.clone())
+            .header("Content-Type", "application/json");
+        for (key, value) in headers {
+            req = req.header(key.as_str(), value.as_str());
+        }
+        let req = match req.body(Body::from(card_body.to_string())) {
+            Ok(req) => req,
+            Err(e) => {
+                log::error!("Failed to build request: {}", e);
+                return Delivery::Rejected;
+            }
+        };
+
+        let last_attempt = i == max_attempts;
+
+        match client.request(req, proxy).await {
+            Ok(m) => {
+                if m.status().as_u16() == 429u16 {
+                    if last_attempt {
+                        log::error!(
+                            "Still rate limited by {} after {} attempts, giving up",
+                            redact_url(&url),
+                            i
+                        );
+                        return Delivery::RateLimited;
+                    }
+                    // Honor the delay requested by the server, within our own limits
+                    let delay = match retry_after(&m) {
+                        Some(delay) => min(delay, retry.max_delay),
+                        None => retry.backoff(i),
+                    };
+                    metrics::retry("rate_limited");
+                    log::info!(
+                        "Detected rate limiting, retrying in {} milliseconds",
+                        delay.as_millis()
+                    );
+                    tokio::time::delay_for(delay).await;
+                } else if m.status().is_success() {
+                    // Workflows webhooks answer with 202 Accepted
+                    return Delivery::Sent;
+                } else if m.status().is_server_error() && !last_attempt {
+                    let delay = retry.backoff(i);
+                    metrics::retry("server_error");
+                    log::info!(
+                        "Got {} from server, retrying in {} milliseconds",
+                        m.status(),
+                        delay.as_millis()
+                    );
+                    tokio::time::delay_for(delay).await;
+                } else {
+                    let status = m.status();
+                    let whole_body = match hyper::body::to_bytes(m.into_body()).await.ok() {
+                        Some(body) => body,
+                        None => Bytes::from("Could not unpack body"),
+                    };
+                    let whole_body_vec = whole_body.iter().cloned().collect::<Vec<u8>>();
+                    let value = from_utf8(&whole_body_vec).unwrap_or("Could not convert bytes");
+                    log::info!(
+                        "Failed to post to {}, got {}: \"{}\"",
+                        redact_url(&url),
+                        status,
+                        value
+                    );
+                    if retryable(status) {
+                        return Delivery::Failed;
                     }
+                    return Delivery::Rejected;
                 }
-                Err(e) => {
-                    log::error!("Caught error posting: {}", e);
-                    return None;
+            }
+            Err(e) => {
+                if last_attempt {
+                    log::error!("Caught error posting to {}: {}", redact_url(&url), e);
+                    return Delivery::Failed;
                 }
-            };
+                let delay = retry.backoff(i);
+                metrics::retry("connection_error");
+                log::info!(
+                    "Caught error posting: {}, retrying in {} milliseconds",
+                    e,
+                    delay.as_millis()
+                );
+                tokio::time::delay_for(delay).await;
+            }
+        };
+    }
+    Delivery::Failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
         }
     }
-    None
+
+    #[test]
+    fn proxy_and_no_proxy_are_resolved_separately() {
+        let vars = env(&[
+            ("HTTPS_PROXY", "http://env-proxy:3128"),
+            ("NO_PROXY", "internal.example.com, .corp"),
+        ]);
+
+        // Flags win, each one on its own
+        let proxy = resolve_proxy(
+            Some("http://flag-proxy:3128"),
+            Some("flag.example.com"),
+            &vars,
+        )
+        .unwrap();
+        assert_eq!(proxy.url, "http://flag-proxy:3128");
+        assert_eq!(proxy.no_proxy, vec!["flag.example.com"]);
+
+        let proxy = resolve_proxy(Some("http://flag-proxy:3128"), None, &vars).unwrap();
+        assert_eq!(proxy.url, "http://flag-proxy:3128");
+        assert_eq!(proxy.no_proxy, vec!["internal.example.com", ".corp"]);
+
+        let proxy = resolve_proxy(None, Some("Flag.example.com"), &vars).unwrap();
+        assert_eq!(proxy.url, "http://env-proxy:3128");
+        assert_eq!(proxy.no_proxy, vec!["flag.example.com"]);
+    }
+
+    #[test]
+    fn lowercase_variables_are_read_too() {
+        let vars = env(&[
+            ("https_proxy", "http://env-proxy:3128"),
+            ("no_proxy", "internal.example.com"),
+        ]);
+        let proxy = resolve_proxy(None, None, &vars).unwrap();
+        assert_eq!(proxy.url, "http://env-proxy:3128");
+        assert_eq!(proxy.no_proxy, vec!["internal.example.com"]);
+    }
+
+    #[test]
+    fn no_proxy_without_a_proxy_is_ignored() {
+        assert!(resolve_proxy(None, Some("internal.example.com"), env(&[])).is_none());
+        assert!(resolve_proxy(None, None, env(&[("HTTPS_PROXY", "")])).is_none());
+    }
+
+    fn policy(jitter: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(5000),
+            jitter: Duration::from_millis(jitter),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = policy(0);
+        let delays: Vec<u128> = (1..=5)
+            .map(|attempt| policy.backoff(attempt).as_millis())
+            .collect();
+        assert_eq!(delays, vec![1000, 2000, 4000, 5000, 5000]);
+
+        // Attempts far past the maximum do not overflow
+        assert_eq!(policy.backoff(0).as_millis(), 1000);
+        assert_eq!(policy.backoff(u32::MAX).as_millis(), 5000);
+    }
+
+    #[test]
+    fn backoff_adds_bounded_jitter() {
+        let policy = policy(250);
+        for _ in 0..100 {
+            let delay = policy.backoff(2).as_millis();
+            assert!((2000..=2250).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn only_timeouts_and_server_errors_are_retryable() {
+        for status in &[408, 500, 502, 503, 504] {
+            assert!(
+                retryable(StatusCode::from_u16(*status).unwrap()),
+                "{}",
+                status
+            );
+        }
+        for status in &[301, 400, 401, 403, 404, 410, 413] {
+            assert!(
+                !retryable(StatusCode::from_u16(*status).unwrap()),
+                "{}",
+                status
+            );
+        }
+    }
+
+    fn response(retry_after: &str) -> Response<Body> {
+        Response::builder()
+            .status(429)
+            .header("Retry-After", retry_after)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn retry_after_takes_seconds() {
+        assert_eq!(retry_after(&response("30")), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after(&response(" 0 ")), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn retry_after_takes_http_dates() {
+        let date = (Utc::now() + chrono::Duration::seconds(120)).to_rfc2822();
+        let wait = retry_after(&response(&date)).unwrap().as_secs();
+        assert!((118..=120).contains(&wait), "{}", wait);
+
+        // Dates in the past mean right away
+        let past = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(retry_after(&response(past)), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn retry_after_ignores_missing_or_invalid_values() {
+        let missing = Response::builder().status(429).body(Body::empty()).unwrap();
+        assert_eq!(retry_after(&missing), None);
+        assert_eq!(retry_after(&response("soon")), None);
+        assert_eq!(retry_after(&response("-5")), None);
+    }
 }
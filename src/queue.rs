@@ -0,0 +1,397 @@
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::config;
+use crate::destination;
+use crate::post;
+
+// Longest wait between two delivery rounds of the same item
+const MAX_ROUND_DELAY: Duration = Duration::from_secs(300);
+
+// How long the outcome of a finished delivery can still be looked up
+const STATUS_RETENTION_HOURS: i64 = 24;
+
+// Alert accepted from atlas and waiting to be delivered to a channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueItem {
+    pub id: String,
+    pub channel: String,
+    pub alert: serde_json::Value,
+    pub received: String,
+
+    #[serde(default)]
+    pub rounds: u32,
+
+    // Every alert of a digest window, sent as one digest card instead of the card of alert
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digest: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Queued,
+    Retrying,
+    Delivered,
+    Failed,
+}
+
+// Outcome of a delivery, as returned by /delivery
+#[derive(Serialize, Debug, Clone)]
+pub struct DeliveryStatus {
+    pub id: String,
+    pub channel: String,
+    pub state: DeliveryState,
+    pub rounds: u32,
+    pub received: String,
+    pub updated: String,
+}
+
+// Delivery queue drained by a background worker. When a data directory is given, each pending
+// alert is also kept in its own file until delivered, so it survives a restart.
+#[derive(Clone)]
+pub struct Queue {
+    pending: Option<PathBuf>,
+    failed: Option<PathBuf>,
+    max_rounds: u32,
+    sender: mpsc::UnboundedSender<QueueItem>,
+    // Deliveries in flight at once, so a backlog does not hit a rate limited webhook all at once
+    workers: Arc<Semaphore>,
+    statuses: Arc<Mutex<HashMap<String, DeliveryStatus>>>,
+}
+
+impl Queue {
+    // Create the queue directories under data_dir, if any, and return the queue along with its receiver
+    pub fn open(
+        data_dir: Option<&str>,
+        max_rounds: u32,
+        concurrency: usize,
+    ) -> std::io::Result<(Queue, mpsc::UnboundedReceiver<QueueItem>)> {
+        let (pending, failed) = match data_dir {
+            Some(data_dir) => {
+                let pending = Path::new(data_dir).join("pending");
+                let failed = Path::new(data_dir).join("failed");
+                fs::create_dir_all(&pending)?;
+                fs::create_dir_all(&failed)?;
+                (Some(pending), Some(failed))
+            }
+            None => (None, None),
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue = Queue {
+            pending,
+            failed,
+            max_rounds,
+            sender,
+            workers: Arc::new(Semaphore::new(concurrency.max(1))),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+        };
+        Ok((queue, receiver))
+    }
+
+    pub fn status(&self, id: &str) -> Option<DeliveryStatus> {
+        let statuses = self
+            .statuses
+            .lock()
+            .expect("Unable to lock delivery statuses");
+        statuses.get(id).cloned()
+    }
+
+    fn set_status(&self, item: &QueueItem, state: DeliveryState) {
+        let now = Utc::now();
+        let mut statuses = self
+            .statuses
+            .lock()
+            .expect("Unable to lock delivery statuses");
+
+        // Forget finished deliveries once they are old enough
+        let cutoff = (now - chrono::Duration::hours(STATUS_RETENTION_HOURS)).to_rfc3339();
+        statuses.retain(|_, status| {
+            !(status.state == DeliveryState::Delivered || status.state == DeliveryState::Failed)
+                || status.updated > cutoff
+        });
+
+        statuses.insert(
+            item.id.clone(),
+            DeliveryStatus {
+                id: item.id.clone(),
+                channel: item.channel.clone(),
+                state,
+                rounds: item.rounds,
+                received: item.received.clone(),
+                updated: now.to_rfc3339(),
+            },
+        );
+    }
+
+    // Persist an alert, and only then hand it to the delivery worker
+    pub async fn push(&self, channel: &str, alert: &serde_json::Value) -> std::io::Result<String> {
+        self.enqueue(channel, alert, Vec::new()).await
+    }
+
+    // Queue the alerts of a digest window, delivered as a single digest card
+    pub async fn push_digest(
+        &self,
+        channel: &str,
+        alerts: &[serde_json::Value],
+    ) -> std::io::Result<String> {
+        let first = alerts.first().cloned().unwrap_or_default();
+        self.enqueue(channel, &first, alerts.to_vec()).await
+    }
+
+    async fn enqueue(
+        &self,
+        channel: &str,
+        alert: &serde_json::Value,
+        digest: Vec<serde_json::Value>,
+    ) -> std::io::Result<String> {
+        let id = format!(
+            "{}-{:08x}",
+            Utc::now().format("%Y%m%d%H%M%S%f"),
+            rand::thread_rng().gen::<u32>()
+        );
+        let item = QueueItem {
+            id: id.clone(),
+            channel: channel.to_string(),
+            alert: alert.clone(),
+            received: Utc::now().to_rfc3339(),
+            rounds: 0,
+            digest,
+        };
+        self.write(&item).await?;
+        self.set_status(&item, DeliveryState::Queued);
+        if self.sender.send(item).is_err() {
+            log::error!("Delivery worker is gone, {} will be sent after restart", id);
+        }
+        Ok(id)
+    }
+
+    // Write the item to a temporary file and rename it into place, so a crash never leaves half an item
+    async fn write(&self, item: &QueueItem) -> std::io::Result<()> {
+        let pending = match &self.pending {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let path = pending.join(format!("{}.json", item.id));
+        let tmp = pending.join(format!("{}.json.tmp", item.id));
+        let contents = serde_json::to_string(item)?;
+        blocking(move || {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)
+        })
+        .await
+    }
+
+    async fn remove(&self, item: &QueueItem) {
+        self.set_status(item, DeliveryState::Delivered);
+        let pending = match &self.pending {
+            Some(pending) => pending,
+            None => return,
+        };
+        let path = pending.join(format!("{}.json", item.id));
+        if let Err(e) = blocking(move || fs::remove_file(&path)).await {
+            log::error!("Failed to remove delivered item {}: {}", item.id, e);
+        }
+    }
+
+    // Move an item we gave up on out of the way, so it can be inspected or replayed by hand
+    async fn fail(&self, item: &QueueItem) {
+        self.set_status(item, DeliveryState::Failed);
+        let (pending, failed) = match (&self.pending, &self.failed) {
+            (Some(pending), Some(failed)) => (pending, failed),
+            _ => return,
+        };
+        let path = pending.join(format!("{}.json", item.id));
+        let failed = failed.join(format!("{}.json", item.id));
+        if let Err(e) = blocking(move || fs::rename(&path, &failed)).await {
+            log::error!("Failed to move item {} to failed: {}", item.id, e);
+        }
+    }
+
+    // Items left over from a previous run, oldest first
+    fn pending(&self) -> Vec<QueueItem> {
+        let pending = match &self.pending {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        let mut paths: Vec<PathBuf> = match fs::read_dir(pending) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+                .collect(),
+            Err(e) => {
+                log::error!("Unable to read queue directory: {}", e);
+                return Vec::new();
+            }
+        };
+        paths.sort();
+
+        paths
+            .iter()
+            .filter_map(|path| {
+                let contents = fs::read_to_string(path).ok()?;
+                match serde_json::from_str(&contents) {
+                    Ok(item) => Some(item),
+                    Err(e) => {
+                        log::error!("Skipping unreadable queue item {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+// Run file system calls off the async workers
+async fn blocking<F, T>(f: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+// Drain the queue, starting with whatever was left pending by a previous run
+pub async fn run(
+    queue: Queue,
+    mut receiver: mpsc::UnboundedReceiver<QueueItem>,
+    config: config::ConfigHash,
+    client: post::HttpsClient,
+    retry: post::RetryPolicy,
+) {
+    let pending = {
+        let queue = queue.clone();
+        blocking(move || Ok(queue.pending()))
+            .await
+            .unwrap_or_default()
+    };
+    if !pending.is_empty() {
+        log::info!("Resuming delivery of {} queued alerts", pending.len());
+    }
+    for item in pending {
+        queue.set_status(&item, DeliveryState::Queued);
+        tokio::spawn(deliver(
+            queue.clone(),
+            item,
+            config.clone(),
+            client.clone(),
+            retry.clone(),
+        ));
+    }
+
+    while let Some(item) = receiver.recv().await {
+        tokio::spawn(deliver(
+            queue.clone(),
+            item,
+            config.clone(),
+            client.clone(),
+            retry.clone(),
+        ));
+    }
+}
+
+async fn deliver(
+    queue: Queue,
+    mut item: QueueItem,
+    config: config::ConfigHash,
+    client: post::HttpsClient,
+    retry: post::RetryPolicy,
+) {
+    loop {
+        let entry = {
+            let config = config.lock().expect("Unable to unlock config HashMap");
+            config.channel(&item.channel)
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                log::error!(
+                    "Channel {} no longer exists, moving {} to failed",
+                    item.channel,
+                    item.id
+                );
+                queue.fail(&item).await;
+                return;
+            }
+        };
+
+        // Only the render and post hold a worker, not the wait between rounds
+        let permit = queue.workers.acquire().await;
+        let rendered = if item.digest.is_empty() {
+            destination::render_channel(&entry, &item.alert)
+        } else {
+            destination::render_digest(&entry, &item.digest)
+        };
+        let card_body = match rendered {
+            Ok(card_body) => card_body,
+            Err(e) => {
+                log::error!("Unable to render {}: {}, moving to failed", item.id, e);
+                queue.fail(&item).await;
+                return;
+            }
+        };
+
+        let sent = destination::send(&entry, &card_body, &client, &retry).await;
+        drop(permit);
+        match sent {
+            post::Delivery::Sent => {
+                log::info!("Delivered queued alert {} to {}", item.id, item.channel);
+                queue.remove(&item).await;
+                return;
+            }
+            // The webhook would turn down every later round just the same
+            post::Delivery::Rejected => {
+                log::error!(
+                    "Channel {} rejected {}, moving to failed",
+                    item.channel,
+                    item.id
+                );
+                queue.fail(&item).await;
+                return;
+            }
+            post::Delivery::RateLimited | post::Delivery::Failed => {}
+        }
+
+        item.rounds += 1;
+        if item.rounds >= queue.max_rounds {
+            log::error!(
+                "Giving up on {} after {} delivery rounds, moving to failed",
+                item.id,
+                item.rounds
+            );
+            queue.fail(&item).await;
+            return;
+        }
+        if let Err(e) = queue.write(&item).await {
+            log::error!("Unable to update queue item {}: {}", item.id, e);
+        }
+        queue.set_status(&item, DeliveryState::Retrying);
+
+        let delay = std::cmp::min(
+            retry
+                .max_delay
+                .checked_mul(2u32.saturating_pow(item.rounds.min(8)))
+                .unwrap_or(MAX_ROUND_DELAY),
+            MAX_ROUND_DELAY,
+        );
+        log::info!(
+            "Delivery of {} failed, next round in {} seconds",
+            item.id,
+            delay.as_secs()
+        );
+        tokio::time::delay_for(delay).await;
+    }
+}
@@ -0,0 +1,340 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// Send alerts whose fields all match to the listed channels
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Route {
+    // Alert field, such as groupId or metricName, mapped to the pattern it has to match
+    #[serde(rename = "match", default)]
+    pub matches: BTreeMap<String, Pattern>,
+
+    pub channels: Vec<String>,
+}
+
+impl Route {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.channels.is_empty() {
+            return Err("does not list any channels".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, alert: &serde_json::Value) -> bool {
+        self.matches
+            .iter()
+            .all(|(field, pattern)| match field_value(alert, field) {
+                Some(value) => pattern.matches(&value),
+                None => false,
+            })
+    }
+}
+
+// Per channel filters on alert fields, each mapped to a list of patterns
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Filter {
+    // Every field listed has to match one of its patterns
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub include: BTreeMap<String, Vec<Pattern>>,
+
+    // Alerts with any field matching one of its patterns are dropped
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exclude: BTreeMap<String, Vec<Pattern>>,
+}
+
+impl Filter {
+    // Returns the rule that dropped the alert, if any
+    pub fn check(&self, alert: &serde_json::Value) -> Result<(), String> {
+        for (field, patterns) in &self.include {
+            let value = field_value(alert, field).unwrap_or_default();
+            if !patterns.iter().any(|pattern| pattern.matches(&value)) {
+                return Err(format!("include {} {:?}", field, patterns));
+            }
+        }
+        for (field, patterns) in &self.exclude {
+            let value = match field_value(alert, field) {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Some(pattern) = patterns.iter().find(|pattern| pattern.matches(&value)) {
+                return Err(format!("exclude {} {:?}", field, pattern));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Look up a field of the alert, nested fields are separated with a dot as in currentValue.units
+pub fn field_value(alert: &serde_json::Value, field: &str) -> Option<String> {
+    let value = field
+        .split('.')
+        .try_fold(alert, |value, key| value.get(key))?;
+    match value {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+// Patterns wrapped in slashes are regexes, patterns with *, ? or [ are globs,
+// and anything else has to match exactly. Compiled once, when the config is read.
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    matcher: Matcher,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Regex(Regex),
+    Glob(glob::Pattern),
+    Exact,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, String> {
+        let matcher = if pattern.len() >= 2 && pattern.starts_with('/') && pattern.ends_with('/') {
+            Regex::new(&pattern[1..pattern.len() - 1])
+                .map(Matcher::Regex)
+                .map_err(|e| format!("invalid regex {}: {}", pattern, e))?
+        } else if pattern.contains(&['*', '?', '['][..]) {
+            glob::Pattern::new(pattern)
+                .map(Matcher::Glob)
+                .map_err(|e| format!("invalid glob {}: {}", pattern, e))?
+        } else {
+            Matcher::Exact
+        };
+        Ok(Pattern {
+            source: pattern.to_string(),
+            matcher,
+        })
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match &self.matcher {
+            Matcher::Regex(regex) => regex.is_match(value),
+            Matcher::Glob(glob) => glob.matches(value),
+            Matcher::Exact => self.source == value,
+        }
+    }
+}
+
+// Patterns are compared, hashed, shown and written back as the text they were read from
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Hash for Pattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.source.hash(state)
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Pattern, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Pattern::new(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+// Channels for an alert posted without ?channel=, from the first route that matches,
+// or else the default channel
+pub fn route(
+    routes: &[Route],
+    default_channel: Option<&String>,
+    alert: &serde_json::Value,
+) -> Vec<String> {
+    match routes.iter().find(|route| route.matches(alert)) {
+        Some(route) => route.channels.clone(),
+        None => default_channel.cloned().into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pattern(source: &str) -> Pattern {
+        Pattern::new(source).unwrap()
+    }
+
+    fn alert() -> serde_json::Value {
+        json!({
+            "id": "5f931f964e5a5d4a6ea7c7a5",
+            "groupId": "5d2f6c93aa9b4c5735a37474",
+            "status": "OPEN",
+            "eventTypeName": "OUTSIDE_METRIC_THRESHOLD",
+            "clusterName": "prod-east",
+            "metricName": "CONNECTIONS_PERCENT",
+            "currentValue": {"number": 92.5, "units": "RAW"},
+        })
+    }
+
+    #[test]
+    fn exact_patterns_match_the_whole_value() {
+        let exact = pattern("prod");
+        assert!(exact.matches("prod"));
+        assert!(!exact.matches("prod-east"));
+        assert!(!exact.matches("Prod"));
+    }
+
+    #[test]
+    fn globs_match_wildcards() {
+        assert!(pattern("prod-*").matches("prod-east"));
+        assert!(!pattern("prod-*").matches("staging-east"));
+        assert!(pattern("prod-?ast").matches("prod-east"));
+        assert!(pattern("HOST_[DU]*").matches("HOST_DOWN"));
+        assert!(!pattern("HOST_[DU]*").matches("HOST_RECOVERED"));
+    }
+
+    #[test]
+    fn slashes_make_regexes() {
+        let regex = pattern("/^(HOST_DOWN|PRIMARY_ELECTED)$/");
+        assert!(regex.matches("HOST_DOWN"));
+        assert!(!regex.matches("HOST_DOWN_AGAIN"));
+
+        // Unanchored regexes match anywhere in the value
+        assert!(pattern("/east/").matches("prod-east-2"));
+
+        // A lone slash is an exact pattern
+        assert!(pattern("/").matches("/"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(Pattern::new("/(unclosed/")
+            .unwrap_err()
+            .starts_with("invalid regex"));
+        assert!(Pattern::new("[unclosed")
+            .unwrap_err()
+            .starts_with("invalid glob"));
+        assert!(serde_yaml::from_str::<Pattern>("\"/(unclosed/\"").is_err());
+    }
+
+    #[test]
+    fn patterns_are_written_back_as_read() {
+        let read: Vec<Pattern> = serde_yaml::from_str("[prod, \"prod-*\", \"/^p/\"]").unwrap();
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            json!(["prod", "prod-*", "/^p/"])
+        );
+        assert_eq!(read[1], pattern("prod-*"));
+        assert_eq!(format!("{:?}", read[1]), "\"prod-*\"");
+    }
+
+    #[test]
+    fn field_values_are_looked_up_as_strings() {
+        let alert = alert();
+        assert_eq!(
+            field_value(&alert, "clusterName").as_deref(),
+            Some("prod-east")
+        );
+        assert_eq!(
+            field_value(&alert, "currentValue.units").as_deref(),
+            Some("RAW")
+        );
+        assert_eq!(
+            field_value(&alert, "currentValue.number").as_deref(),
+            Some("92.5")
+        );
+        assert_eq!(field_value(&alert, "currentValue.missing"), None);
+        assert_eq!(field_value(&alert, "clusterName.nested"), None);
+        assert_eq!(
+            field_value(&json!({"replicaSetName": null}), "replicaSetName"),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_need_every_field_to_match() {
+        let route: Route = serde_yaml::from_str(
+            "match:\n  clusterName: \"prod-*\"\n  currentValue.units: RAW\nchannels: [one]\n",
+        )
+        .unwrap();
+        assert!(route.matches(&alert()));
+
+        let mut other = alert();
+        other["currentValue"]["units"] = json!("PERCENT");
+        assert!(!route.matches(&other));
+
+        let mut missing = alert();
+        missing.as_object_mut().unwrap().remove("clusterName");
+        assert!(!route.matches(&missing));
+    }
+
+    #[test]
+    fn first_matching_route_wins_over_the_default() {
+        let routes: Vec<Route> = serde_yaml::from_str(
+            "- match: {clusterName: \"staging-*\"}\n  channels: [staging]\n\
+             - match: {clusterName: \"prod-*\"}\n  channels: [one, two]\n\
+             - match: {status: OPEN}\n  channels: [open]\n",
+        )
+        .unwrap();
+        let default = "fallback".to_string();
+        assert_eq!(route(&routes, Some(&default), &alert()), vec!["one", "two"]);
+
+        let mut dev = alert();
+        dev["clusterName"] = json!("dev");
+        dev["status"] = json!("CLOSED");
+        assert_eq!(route(&routes, Some(&default), &dev), vec!["fallback"]);
+        assert!(route(&routes, None, &dev).is_empty());
+    }
+
+    #[test]
+    fn filters_include_and_exclude() {
+        let filter: Filter = serde_yaml::from_str(
+            "include:\n  clusterName: [\"prod-*\", staging]\n  status: [OPEN]\n\
+             exclude:\n  metricName: [\"/^CONNECTIONS/\"]\n",
+        )
+        .unwrap();
+
+        let mut alert = alert();
+        assert_eq!(
+            filter.check(&alert),
+            Err("exclude metricName \"/^CONNECTIONS/\"".to_string())
+        );
+
+        alert["metricName"] = json!("DISK_PARTITION_SPACE_USED_DATA");
+        assert_eq!(filter.check(&alert), Ok(()));
+
+        // Alerts without an excluded field are kept
+        alert.as_object_mut().unwrap().remove("metricName");
+        assert_eq!(filter.check(&alert), Ok(()));
+
+        // Every included field has to match one of its patterns
+        alert["status"] = json!("CLOSED");
+        assert_eq!(
+            filter.check(&alert),
+            Err("include status [\"OPEN\"]".to_string())
+        );
+
+        // A missing included field is checked as an empty value
+        alert["status"] = json!("OPEN");
+        alert.as_object_mut().unwrap().remove("clusterName");
+        assert!(filter.check(&alert).is_err());
+    }
+
+    #[test]
+    fn empty_filters_keep_everything() {
+        assert_eq!(Filter::default().check(&alert()), Ok(()));
+    }
+}
@@ -1,20 +1,42 @@
+use futures_util::future::join_all;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::json;
 use std::str::from_utf8;
 
+use crate::acknowledge;
+use crate::admin;
+use crate::auth;
+use crate::catalog;
 use crate::config;
+use crate::dedup;
+use crate::destination;
+use crate::digest;
+use crate::metrics;
 use crate::post;
-use crate::transform;
+use crate::queue;
+
+// Shared state handed to every request
+#[derive(Clone)]
+pub struct State {
+    pub config: config::ConfigHash,
+    pub client: post::HttpsClient,
+    pub retry: post::RetryPolicy,
+    pub queue: Option<queue::Queue>,
+    pub admin: Option<admin::Admin>,
+    pub dedup: Option<dedup::Dedup>,
+    pub digests: digest::Digests,
+}
 
 // This is our service handler. It receives a Request, routes on its
 // path, and returns a Future of a Response.
 pub async fn echo(
     req: Request<Body>,
-    config: config::ConfigHash,
+    state: State,
 ) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
     match (req.method(), req.uri().path()) {
         // Serve some instructions at /
         (&Method::GET, "/") => Ok(Response::new(Body::from(
-            "Paths:\n\t/echo: Returns json back\n\t/stdout: Write posted json to stdout\n\t/alert: Send alert to teams\n\t/testalert: Returns body of post to teams",
+            "Paths:\n\t/echo: Returns json back\n\t/stdout: Write posted json to stdout\n\t/alert: Send alert to teams\n\t/testalert: Returns body of post to teams\n\t/delivery: Returns status of a queued delivery\n\t/channels: Manage channels at runtime\n\t/metrics: Prometheus metrics\n\t/catalog/unknown: Event types missing from the catalog\n\t/acknowledge: Acknowledge an alert from a card button",
         ))),
 
         // Return posted body
@@ -38,11 +60,30 @@ pub async fn echo(
 
         // echo transformed card with received variables
         (&Method::POST, "/testalert") => {
-            let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+            let (parts,body) = req.into_parts();
+            let whole_body = hyper::body::to_bytes(body).await?;
             let whole_body_vec = whole_body.iter().cloned().collect::<Vec<u8>>();
             let value = from_utf8(&whole_body_vec).to_owned()?;
             let value_json: serde_json::Value = serde_json::from_str(value)?;
-            let card_body = transform::create_card(value_json)?;
+            // A channel renders the card just as /alert would, including its template
+            // Without one, the global theme still applies
+            let (entry, theme, links) = {
+                let config = state.config.lock().expect("Unable to lock config");
+                let entry = config::channels(&parts).first().and_then(|channel| config.channel(channel));
+                (entry, config.theme.clone().unwrap_or_default(), config.links.clone())
+            };
+            let card_body = match entry {
+                // Previews are unauthenticated, so they never carry a signed acknowledge link
+                Some(mut entry) => {
+                    entry.acknowledge = None;
+                    destination::render_channel(&entry, &value_json)?
+                },
+                None => {
+                    let locale = config::locale(&parts).unwrap_or_else(|| catalog::DEFAULT_LOCALE.to_string());
+                    let actions = links.map(|links| links.actions(&value_json, &locale, None)).unwrap_or_default();
+                    destination::render(&config::kind(&parts).unwrap_or_default(), &value_json, &theme, &locale, actions)?
+                },
+            };
             Ok(Response::new(Body::from(card_body.to_string())))
         }
 
@@ -50,41 +91,115 @@ pub async fn echo(
         (&Method::POST, "/alert") => {
             let (parts,body) = req.into_parts();
             let whole_body = hyper::body::to_bytes(body).await?;
+
+            // Reject unauthenticated posts before the body is even parsed, the signature
+            // has to be checked against the raw bytes atlas signed. Posts without ?channel=
+            // are held to the global auth here, and to the auth of each routed channel below.
+            let routed = config::channels(&parts).is_empty();
+            let requested = if routed {
+                None
+            } else {
+                config::match_channels(&parts, state.config.clone())
+            };
+            let verified = match &requested {
+                Some(targets) => targets
+                    .iter()
+                    .try_for_each(|(_, entry)| authenticate(entry, &parts, &whole_body)),
+                None if routed => {
+                    let global = state.config.lock().expect("Unable to lock config").auth.clone();
+                    auth::verify(global.as_ref(), &parts)
+                },
+                None => Ok(()),
+            };
+            if let Err(e) = verified {
+                let mut response = Response::default();
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                log::error!("Rejected post to {}: {}", parts.uri.path(), e);
+                return Ok(response);
+            }
+
             let whole_body_vec = whole_body.iter().cloned().collect::<Vec<u8>>();
             let value = from_utf8(&whole_body_vec).to_owned()?;
             let value_json: serde_json::Value = serde_json::from_str(value)?;
-            let card_body = transform::create_card(value_json.clone())?;
 
-            log::info!("Received message: {}", value_json);
-
-            match config::match_channel(&parts, config) {
-                Some(url) => {
-                    match post::post_retry(&card_body, url).await {
-                        Some(true) => {
-                            let mut response = Response::default();
-                            *response.status_mut() = StatusCode::OK;
-                            log::info!("Successfully posted: \"{}\"", card_body);
-                            Ok(response)
-                        },
-                        Some(false) => {
-                            let mut response = Response::default();
-                            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
-                            log::error!("Posting to teams failed for id: {}, bulk post failure", value_json["id"]);
-                            Ok(response)
-                        },
-                        None => {
-                            let mut response = Response::default();
-                            *response.status_mut() = StatusCode::BAD_REQUEST;
-                            log::error!("Post failed for id: {}", value_json["id"]);
-                            Ok(response)
-                        }
-                    }
+            // Without ?channel=, the routing rules pick the channels from the alert itself
+            let targets: Vec<(String, config::ConfigEntry)> = match requested {
+                Some(targets) => targets,
+                None if routed => {
+                    let config = state.config.lock().expect("Unable to lock config");
+                    config
+                        .route(&value_json)
+                        .into_iter()
+                        .filter_map(|channel| config.channel(&channel).map(|entry| (channel, entry)))
+                        .collect()
                 },
-                None => {
+                None => Vec::new(),
+            };
+            for (channel, entry) in targets.iter().filter(|_| routed) {
+                if let Err(e) = authenticate(entry, &parts, &whole_body) {
                     let mut response = Response::default();
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+                    log::error!("Rejected post routed to {}: {}", channel, e);
+                    return Ok(response);
+                }
+            }
+            if targets.is_empty() {
+                let mut response = Response::default();
+                if anonymous(&state, &parts) {
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+                    log::error!("Rejected post to {}: no channel to authenticate against", parts.uri.path());
+                } else {
                     *response.status_mut() = StatusCode::BAD_REQUEST;
                     log::error!("Bad channel specified for id: {}", value_json["id"]);
-                    Ok(response)
+                }
+                return Ok(response);
+            }
+
+            // Only authenticated posts are logged and counted in /catalog/unknown
+            log::info!("Received message: {}", value_json);
+            catalog::observe(&value_json);
+            if routed {
+                let channels: Vec<&String> = targets.iter().map(|(channel, _)| channel).collect();
+                log::info!("Routed id: {} to {:?}", value_json["id"], channels);
+            }
+
+            // Deliver to every channel at once, so a slow or failing destination does not hold up the others
+            let mut outcomes = join_all(targets.into_iter().map(|(channel, entry)| {
+                let state = &state;
+                let value_json = &value_json;
+                async move {
+                    let outcome = accept(state, &channel, entry, value_json).await;
+                    (channel, outcome)
+                }
+            }))
+            .await;
+
+            // A single channel answers as it always has, several report back per channel
+            let outcome = match outcomes.len() {
+                1 => outcomes.remove(0).1?,
+                _ => aggregate(outcomes, &value_json),
+            };
+            let mut response = Response::default();
+            *response.status_mut() = outcome.status;
+            if !outcome.body.is_null() {
+                *response.body_mut() = Body::from(outcome.body.to_string());
+            }
+            Ok(response)
+        }
+
+        // Look up the outcome of a queued delivery
+        (&Method::GET, "/delivery") => {
+            let (parts, _) = req.into_parts();
+            let status = match (&state.queue, config::delivery_id(&parts)) {
+                (Some(queue), Some(id)) => queue.status(&id),
+                _ => None,
+            };
+            match status {
+                Some(status) => Ok(Response::new(Body::from(serde_json::to_string(&status)?))),
+                None => {
+                    let mut not_found = Response::default();
+                    *not_found.status_mut() = StatusCode::NOT_FOUND;
+                    Ok(not_found)
                 }
             }
         }
@@ -94,10 +209,54 @@ pub async fn echo(
             Ok(Response::new(Body::from("ok".to_string())))
         }
 
+        // Event types received without a description, to be added to the catalog
+        (&Method::GET, "/catalog/unknown") => {
+            Ok(Response::new(Body::from(serde_json::to_string(&catalog::unknown())?)))
+        }
+
+        // Forwarding health in the prometheus text format
+        (&Method::GET, "/metrics") => {
+            let channels = state.config.lock().expect("Unable to lock config").channels.len();
+            let mut response = Response::new(Body::from(metrics::gather(channels)?));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_str(&metrics::content_type())?,
+            );
+            Ok(response)
+        }
+
         // echo transformed card with received variables
         (&Method::GET, "/config") => {
-            let config = config.lock().expect("Unable to lock config");
-            Ok(Response::new(Body::from(format!("{:#?}",config))))
+            // Full webhook urls are only shown to admins that explicitly ask for them
+            let (parts, _) = req.into_parts();
+            let reveal = config::reveal(&parts);
+            let authorized = state
+                .admin
+                .as_ref()
+                .map(|admin| admin::authorized(&parts.headers, admin))
+                .unwrap_or(false);
+            if reveal && !authorized {
+                let mut response = Response::default();
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                return Ok(response);
+            }
+            let config = state.config.lock().expect("Unable to lock config");
+            let body = if reveal {
+                serde_json::to_value(&*config)?
+            } else {
+                config.redacted()
+            };
+            Ok(Response::new(Body::from(serde_json::to_string_pretty(&body)?)))
+        }
+
+        // Form behind the acknowledge buttons, which calls the atlas api
+        (_, "/acknowledge") => {
+            acknowledge::handle(req, state).await
+        }
+
+        // Manage channels at runtime
+        (_, path) if path == "/channels" || path.starts_with("/channels/") => {
+            admin::channels(req, state).await
         }
 
         // Return the 404 Not Found for other routes.
@@ -108,3 +267,216 @@ pub async fn echo(
         }
     }
 }
+
+// Result of handing an alert to one channel, with the body to answer the post with
+struct Outcome {
+    status: StatusCode,
+    body: serde_json::Value,
+}
+
+type Accepted = Result<Outcome, Box<dyn std::error::Error + Send + Sync>>;
+
+impl Outcome {
+    fn new(status: StatusCode, body: serde_json::Value) -> Outcome {
+        Outcome { status, body }
+    }
+}
+
+// Check a post against the channel's auth settings and webhook secret
+fn authenticate(
+    entry: &config::ConfigEntry,
+    parts: &http::request::Parts,
+    body: &[u8],
+) -> Result<(), String> {
+    auth::verify(entry.auth.as_ref(), parts)?;
+    match &entry.secret {
+        Some(secret) => auth::verify_signature(secret, parts, body),
+        None => Ok(()),
+    }
+}
+
+// Deliver an alert to one channel, either through its digest, the queue, or right away
+async fn accept(
+    state: &State,
+    channel: &str,
+    entry: config::ConfigEntry,
+    value_json: &serde_json::Value,
+) -> Accepted {
+    // Only configured channel names are used as labels, anything else could grow without bound
+    metrics::alert_received(channel, value_json);
+
+    // Alerts the channel does not want are acknowledged without being forwarded
+    if let Some(filter) = &entry.filter {
+        if let Err(rule) = filter.check(value_json) {
+            log::info!(
+                "Filtered id: {} for channel {} by rule: {}",
+                value_json["id"],
+                channel,
+                rule
+            );
+            metrics::filtered(channel);
+            return Ok(Outcome::new(StatusCode::OK, json!({ "filtered": rule })));
+        }
+    }
+
+    // Atlas retries and re-fired alerts are acknowledged without being forwarded again. A retry
+    // arriving while the first post is still being delivered is answered with 409, so atlas
+    // tries again later instead of counting an alert as delivered that may still fail.
+    let check = state
+        .dedup
+        .as_ref()
+        .map(|dedup| dedup.check(channel, value_json));
+    let claim = match check {
+        Some(dedup::Check::Duplicate) => {
+            log::info!(
+                "Dropped duplicate id: {} with status: {} for channel {}",
+                value_json["id"],
+                value_json["status"],
+                channel
+            );
+            metrics::duplicate(channel);
+            return Ok(Outcome::new(StatusCode::OK, serde_json::Value::Null));
+        }
+        Some(dedup::Check::InFlight) => {
+            log::info!(
+                "Deferred id: {} with status: {} for channel {}, still being delivered",
+                value_json["id"],
+                value_json["status"],
+                channel
+            );
+            return Ok(Outcome::new(StatusCode::CONFLICT, serde_json::Value::Null));
+        }
+        Some(dedup::Check::New(claim)) => Some(claim),
+        None => None,
+    };
+
+    // Only alerts that were delivered, queued or buffered count for the dedup window
+    let outcome = deliver(state, channel, &entry, value_json).await;
+    if let (Some(claim), Ok(outcome)) = (claim, &outcome) {
+        if outcome.status.is_success() {
+            claim.accept();
+        }
+    }
+    outcome
+}
+
+// Hand an alert to its digest, the queue, or post it right away
+async fn deliver(
+    state: &State,
+    channel: &str,
+    entry: &config::ConfigEntry,
+    value_json: &serde_json::Value,
+) -> Accepted {
+    let card_body = destination::render_channel(entry, value_json)?;
+
+    // Hold the alert back until its digest window is over
+    if entry.digest.is_some() {
+        let group = state.digests.add(channel, entry, value_json.clone());
+        log::info!("Buffered id: {} for digest {}", value_json["id"], group);
+        return Ok(Outcome::new(
+            StatusCode::ACCEPTED,
+            json!({ "digest": group }),
+        ));
+    }
+
+    // Queue the alert and acknowledge it right away, the worker takes care of delivery
+    if let Some(queue) = &state.queue {
+        return match queue.push(channel, value_json).await {
+            Ok(queue_id) => {
+                log::info!("Queued id: {} as {}", value_json["id"], queue_id);
+                Ok(Outcome::new(
+                    StatusCode::ACCEPTED,
+                    json!({ "id": queue_id }),
+                ))
+            }
+            Err(e) => {
+                log::error!("Unable to queue id: {}, {}", value_json["id"], e);
+                Ok(Outcome::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    serde_json::Value::Null,
+                ))
+            }
+        };
+    }
+
+    match destination::send(entry, &card_body, &state.client, &state.retry).await {
+        post::Delivery::Sent => {
+            log::info!(
+                "Successfully posted id: {} to channel {}",
+                value_json["id"],
+                channel
+            );
+            Ok(Outcome::new(StatusCode::OK, serde_json::Value::Null))
+        }
+        post::Delivery::RateLimited => {
+            log::error!(
+                "Posting to {:?} failed for id: {}, bulk post failure",
+                entry.kind,
+                value_json["id"]
+            );
+            Ok(Outcome::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                serde_json::Value::Null,
+            ))
+        }
+        post::Delivery::Failed | post::Delivery::Rejected => {
+            log::error!("Post failed for id: {}", value_json["id"]);
+            Ok(Outcome::new(
+                StatusCode::BAD_REQUEST,
+                serde_json::Value::Null,
+            ))
+        }
+    }
+}
+
+// Combine the outcomes of a fan-out into one answer listing every channel. The status is that of
+// the channels when they all agree on success or failure, and 207 Multi-Status when they do not.
+fn aggregate(outcomes: Vec<(String, Accepted)>, value_json: &serde_json::Value) -> Outcome {
+    let mut statuses = Vec::new();
+    let mut channels = serde_json::Map::new();
+    for (channel, outcome) in outcomes {
+        let outcome = outcome.unwrap_or_else(|e| {
+            log::error!(
+                "Unable to deliver id: {} to channel {}: {}",
+                value_json["id"],
+                channel,
+                e
+            );
+            Outcome::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": e.to_string() }),
+            )
+        });
+        let mut result = json!({ "status": outcome.status.as_u16() });
+        if let serde_json::Value::Object(fields) = outcome.body {
+            for (key, value) in fields {
+                result[key] = value;
+            }
+        }
+        statuses.push(outcome.status);
+        channels.insert(channel, result);
+    }
+
+    let succeeded = statuses.iter().filter(|status| status.is_success()).count();
+    let status = if succeeded == 0 || succeeded == statuses.len() {
+        statuses[0]
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Outcome::new(status, json!({ "channels": channels }))
+}
+
+// Posts that match no channel can only be held to the global auth. Without one, they are
+// treated as unauthenticated whenever any channel requires auth, so that channel names
+// and routing rules can not be probed by posting alerts.
+fn anonymous(state: &State, parts: &http::request::Parts) -> bool {
+    let config = state.config.lock().expect("Unable to lock config");
+    match &config.auth {
+        Some(global) if !global.is_empty() => auth::verify(Some(global), parts).is_err(),
+        _ => config
+            .channels
+            .keys()
+            .filter_map(|name| config.channel(name))
+            .any(|entry| entry.secret.is_some() || entry.auth.is_some_and(|auth| !auth.is_empty())),
+    }
+}
@@ -0,0 +1,262 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::DateTime;
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError};
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::links::Action;
+use crate::theme::{self, Theme};
+use crate::transform;
+
+// Reformat an rfc3339 timestamp with a strftime format, leaving anything else untouched
+fn format_time(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let param = |index: usize| {
+        h.param(index)
+            .and_then(|param| param.value().as_str())
+            .ok_or_else(|| RenderError::new("format_time takes a timestamp and a format"))
+    };
+    let (time, format) = (param(0)?, param(1)?);
+
+    // chrono panics on unknown specifiers once the time is written out, so check them first
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(RenderError::new(format!(
+            "format_time: invalid format {}",
+            format
+        )));
+    }
+    let formatted = match DateTime::parse_from_rfc3339(time) {
+        Ok(time) => time.format_with_items(items.into_iter()).to_string(),
+        Err(_) => time.to_string(),
+    };
+    out.write(&escape_json(&formatted))?;
+    Ok(())
+}
+
+// Insert a value as json, written out as is rather than escaped into a string
+fn json(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .map(|param| param.value().to_string())
+        .unwrap_or_else(|| "null".to_string());
+    out.write(&value)?;
+    Ok(())
+}
+
+// Templates render json, so values are escaped as the inside of a json string
+fn escape_json(value: &str) -> String {
+    let quoted = serde_json::Value::String(value.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+// Colors follow the theme of the channel the template renders for, passed along with the data
+fn status_color(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let status = h
+        .param(0)
+        .and_then(|param| param.value().as_str())
+        .unwrap_or_default();
+    let colors = &ctx.data()["_status_colors"];
+    let color = colors
+        .get(status)
+        .unwrap_or(&colors["unknown"])
+        .as_str()
+        .unwrap_or_default();
+    out.write(color)?;
+    Ok(())
+}
+
+// Descriptions in the locale of the channel
+fn event_description(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let event_type = h
+        .param(0)
+        .and_then(|param| param.value().as_str())
+        .unwrap_or_default();
+    let locale = ctx.data()["_locale"].as_str().unwrap_or_default();
+    out.write(&escape_json(&transform::describe_event(event_type, locale)))?;
+    Ok(())
+}
+
+// The templates of the config, compiled once when it is read and shared by every channel
+#[derive(Clone)]
+pub struct Registry(Arc<Handlebars<'static>>);
+
+impl Registry {
+    pub fn compile(sources: &BTreeMap<String, String>) -> Result<Registry, String> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(escape_json);
+        handlebars.register_helper("status_color", Box::new(status_color));
+        handlebars.register_helper("event_description", Box::new(event_description));
+        handlebars.register_helper("format_time", Box::new(format_time));
+        handlebars.register_helper("json", Box::new(json));
+        for (name, source) in sources {
+            handlebars
+                .register_template_string(name, source)
+                .map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(Registry(Arc::new(handlebars)))
+    }
+
+    fn names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.0.get_templates().keys().collect();
+        names.sort();
+        names
+    }
+
+    // Render an alert with a user supplied template, which has to produce the json posted to the webhook
+    pub fn render(
+        &self,
+        name: &str,
+        alert_json: &serde_json::Value,
+        theme: &Theme,
+        locale: &str,
+        actions: Vec<Action>,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let status_colors: BTreeMap<&str, String> = theme::STATUSES
+            .iter()
+            .chain(std::iter::once(&"unknown"))
+            .map(|status| (*status, transform::status_color(status, theme)))
+            .collect();
+        let data = serde_json::json!({
+            "alert": alert_json,
+            "card": transform::card_context(alert_json, theme, locale, actions),
+            "_status_colors": status_colors,
+            "_locale": locale,
+        });
+        let rendered = self
+            .0
+            .render(name, &data)
+            .map_err(|e| format!("unable to render template {}: {}", name, e))?;
+        serde_json::from_str(&rendered)
+            .map_err(|e| format!("template {} did not render valid json: {}", name, e).into())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::compile(&BTreeMap::new()).expect("Unable to build template registry")
+    }
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+// Registries are equal when they hold the same compiled templates
+impl PartialEq for Registry {
+    fn eq(&self, other: &Registry) -> bool {
+        self.0.get_templates() == other.0.get_templates()
+    }
+}
+
+impl Eq for Registry {}
+
+impl Hash for Registry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.names().hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry(source: &str) -> Registry {
+        let mut sources = BTreeMap::new();
+        sources.insert("test".to_string(), source.to_string());
+        Registry::compile(&sources).unwrap()
+    }
+
+    fn render(source: &str, theme: &Theme) -> Result<serde_json::Value, String> {
+        let alert = json!({
+            "id": "5f931f964e5a5d4a6ea7c7a5",
+            "status": "OPEN",
+            "eventTypeName": "HOST_DOWN",
+            "created": "2020-10-23T09:12:31Z",
+            "clusterName": "say \"hi\"",
+        });
+        registry(source)
+            .render("test", &alert, theme, "en", Vec::new())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn formats_times() {
+        let rendered = render(
+            r#"{"text": "{{format_time alert.created "%H:%M %d/%m"}}"}"#,
+            &Theme::default(),
+        );
+        assert_eq!(rendered, Ok(json!({"text": "09:12 23/10"})));
+    }
+
+    #[test]
+    fn invalid_time_formats_fail_the_render() {
+        let rendered = render(
+            r#"{"text": "{{format_time alert.created "%Q"}}"}"#,
+            &Theme::default(),
+        );
+        assert!(rendered.unwrap_err().contains("invalid format %Q"));
+    }
+
+    #[test]
+    fn status_colors_follow_the_theme() {
+        let source =
+            r#"{"open": "{{status_color alert.status}}", "other": "{{status_color "NEW"}}"}"#;
+        let theme: Theme = serde_yaml::from_str(
+            "statuses:\n  OPEN:\n    color: \"#123456\"\n  unknown:\n    color: \"#abcdef\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            render(source, &theme),
+            Ok(json!({"open": "123456", "other": "ABCDEF"}))
+        );
+    }
+
+    #[test]
+    fn values_are_escaped_for_json() {
+        let rendered = render(
+            r#"{"text": "{{alert.clusterName}}", "raw": {{json alert.clusterName}}}"#,
+            &Theme::default(),
+        );
+        assert_eq!(
+            rendered,
+            Ok(json!({"text": "say \"hi\"", "raw": "say \"hi\""}))
+        );
+    }
+
+    #[test]
+    fn compile_errors_name_the_template() {
+        let mut sources = BTreeMap::new();
+        sources.insert("broken".to_string(), "{{#if}}".to_string());
+        assert!(Registry::compile(&sources)
+            .unwrap_err()
+            .starts_with("broken: "));
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::routing;
+
+// Statuses atlas and ops manager send, anything else is themed as "unknown"
+pub const STATUSES: &[&str] = &["OPEN", "CLOSED", "INFORMATIONAL", "TRACKING", "CANCELLED"];
+
+// Colors, titles and icons of the cards, set globally and overridden per channel
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Theme {
+    // Keyed by alert status, or "unknown"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub statuses: BTreeMap<String, StatusTheme>,
+
+    // Colors picked by eventTypeName, first match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub severities: Vec<Severity>,
+}
+
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatusTheme {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    // Image shown next to the activity title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+#[derive(Hash, Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Severity {
+    pub color: String,
+
+    // eventTypeName patterns, exact, glob or /regex/ as in routes
+    pub events: Vec<routing::Pattern>,
+}
+
+impl Theme {
+    pub fn validate(&self) -> Result<(), String> {
+        for (status, theme) in &self.statuses {
+            if status != "unknown" && !STATUSES.contains(&status.as_str()) {
+                return Err(format!(
+                    "unknown status {}, expected one of {} or unknown",
+                    status,
+                    STATUSES.join(", ")
+                ));
+            }
+            if let Some(color) = &theme.color {
+                check_color(color).map_err(|e| format!("{}: {}", status, e))?;
+            }
+        }
+        for (index, severity) in self.severities.iter().enumerate() {
+            check_color(&severity.color).map_err(|e| format!("severities[{}]: {}", index, e))?;
+        }
+        Ok(())
+    }
+
+    // Channel settings win over global ones, field by field for statuses
+    pub fn merge(&self, channel: &Theme) -> Theme {
+        let mut statuses = self.statuses.clone();
+        for (status, theme) in &channel.statuses {
+            let merged = statuses.entry(status.clone()).or_default();
+            if theme.color.is_some() {
+                merged.color = theme.color.clone();
+            }
+            if theme.title.is_some() {
+                merged.title = theme.title.clone();
+            }
+            if theme.icon.is_some() {
+                merged.icon = theme.icon.clone();
+            }
+        }
+        let severities = if channel.severities.is_empty() {
+            self.severities.clone()
+        } else {
+            channel.severities.clone()
+        };
+        Theme {
+            statuses,
+            severities,
+        }
+    }
+
+    pub fn status(&self, status: &str) -> Option<&StatusTheme> {
+        if STATUSES.contains(&status) {
+            self.statuses.get(status)
+        } else {
+            self.statuses.get("unknown")
+        }
+    }
+
+    pub fn severity_color(&self, event_type: &str) -> Option<String> {
+        self.severities
+            .iter()
+            .find(|severity| {
+                severity
+                    .events
+                    .iter()
+                    .any(|pattern| pattern.matches(event_type))
+            })
+            .map(|severity| normalize_color(&severity.color))
+    }
+}
+
+// Colors are six hex digits, with or without a leading #
+fn check_color(color: &str) -> Result<(), String> {
+    let hex = color.trim_start_matches('#');
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(format!("invalid color {}, expected six hex digits", color))
+    }
+}
+
+pub fn normalize_color(color: &str) -> String {
+    color.trim_start_matches('#').to_uppercase()
+}
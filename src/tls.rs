@@ -0,0 +1,185 @@
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request};
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::server;
+
+// Clients that connect but never finish the handshake are dropped after this long
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Pause after failing to accept a connection, as hyper's own listener does
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+// Files used to terminate https on the listener
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert: String,
+    pub key: String,
+
+    // When set, clients must present a certificate signed by one of these CAs
+    pub client_ca: Option<String>,
+
+    pub reload_interval: Duration,
+}
+
+impl TlsOptions {
+    fn files(&self) -> Vec<&str> {
+        let mut files = vec![self.cert.as_str(), self.key.as_str()];
+        if let Some(client_ca) = &self.client_ca {
+            files.push(client_ca.as_str());
+        }
+        files
+    }
+}
+
+type SharedConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+fn open(file: &str) -> Result<BufReader<File>, Box<dyn std::error::Error + Send + Sync>> {
+    File::open(file)
+        .map(BufReader::new)
+        .map_err(|e| format!("unable to open {}: {}", file, e).into())
+}
+
+// Build the rustls config from the pem files on disk
+pub fn load(
+    options: &TlsOptions,
+) -> Result<ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let certs = certs(&mut open(&options.cert)?)
+        .map_err(|_| format!("unable to parse certificates in {}", options.cert))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", options.cert).into());
+    }
+
+    // Accept both pkcs8 and traditional rsa keys
+    let mut keys = pkcs8_private_keys(&mut open(&options.key)?)
+        .map_err(|_| format!("unable to parse private key in {}", options.key))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&options.key)?)
+            .map_err(|_| format!("unable to parse private key in {}", options.key))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("no private key found in {}", options.key))?;
+
+    let mut config = match &options.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots
+                .add_pem_file(&mut open(client_ca)?)
+                .map_err(|_| format!("unable to parse certificates in {}", client_ca))?;
+            if added == 0 {
+                return Err(format!("no certificates found in {}", client_ca).into());
+            }
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config.set_single_cert(certs, key)?;
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    Ok(config)
+}
+
+fn modified(options: &TlsOptions) -> Vec<Option<SystemTime>> {
+    options
+        .files()
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+// Reload the certificates whenever one of the files changes, keeping the current ones on error
+async fn watch(options: TlsOptions, shared: SharedConfig) {
+    let mut last = modified(&options);
+    loop {
+        tokio::time::delay_for(options.reload_interval).await;
+        let current = modified(&options);
+        if current == last {
+            continue;
+        }
+        last = current;
+        match load(&options) {
+            Ok(config) => {
+                *shared.write().expect("Unable to lock tls config") = Arc::new(config);
+                log::info!("Reloaded tls certificates from {}", options.cert);
+            }
+            Err(e) => {
+                log::error!("Keeping current tls certificates, unable to reload: {}", e);
+            }
+        }
+    }
+}
+
+// Accept https connections, each one handed to server::echo once the handshake is done
+pub async fn serve(
+    addr: SocketAddr,
+    options: TlsOptions,
+    state: server::State,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let shared: SharedConfig = Arc::new(RwLock::new(Arc::new(load(&options)?)));
+    if options.reload_interval > Duration::from_secs(0) {
+        tokio::spawn(watch(options.clone(), shared.clone()));
+    }
+
+    let mut listener = TcpListener::bind(&addr).await?;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            // Like hyper, move on right away when a client gave up before being accepted.
+            // Anything else, such as running out of file descriptors, would only fail again
+            // immediately, so wait a little instead of spinning.
+            Err(e) if connection_error(&e) => continue,
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+                tokio::time::delay_for(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        // New connections always pick up the latest certificates
+        let acceptor = TlsAcceptor::from(shared.read().expect("Unable to lock tls config").clone());
+        let state = state.clone();
+        tokio::spawn(async move {
+            let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::warn!("Tls handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!(
+                        "Tls handshake with {} timed out after {} seconds",
+                        peer,
+                        HANDSHAKE_TIMEOUT.as_secs()
+                    );
+                    return;
+                }
+            };
+            let service = service_fn(move |req: Request<Body>| server::echo(req, state.clone()));
+            // Clients closing the connection first is routine, so keep it out of the error log
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                log::debug!("Error serving connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
@@ -1,15 +1,294 @@
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use crate::catalog;
+use crate::links::Action;
+use crate::theme::{self, Theme};
+
 #[derive(Hash, Eq, Default, PartialEq, Debug, Clone, Serialize, Deserialize, Ord, PartialOrd)]
 struct FactEntry {
     pub name: String,
     pub value: String,
+
+    // The value without the json quotes the MessageCard has always shown, for the other formats
+    #[serde(skip)]
+    pub text: String,
+}
+
+impl FactEntry {
+    fn new(name: String, value: String) -> FactEntry {
+        FactEntry {
+            name,
+            text: value.clone(),
+            value,
+        }
+    }
+}
+
+const ACTIVITY_IMAGE: &str = "https://company-30077.frontify.com/api/screen/download/eyJpZCI6MzQwMzI2NCwidmVyc2lvbiI6IjIwMTktMDgtMDIgMTk6Mjg6MDYifQ:frontify:DkJTntON9g0YByA8Q_M4vJX_XxO7je1rn7PJN6RJ_TI/?download&title_as_filename&track";
+
+// Title, colors and subtitle shared by every card format
+struct CardStatus {
+    title: serde_json::Value,
+    color: String,
+    style: &'static str,
+    subtitle: serde_json::Value,
+    icon: String,
+}
+
+const GREEN: &str = "12924F";
+const YELLOW: &str = "FFB900";
+const RED: &str = "D7000C";
+const GRAY: &str = "8A8886";
+const OTHER: &str = "0078D7";
+
+// Most alerts listed on a digest card, to stay within the size limits of the webhooks
+const DIGEST_MAX_OCCURRENCES: usize = 50;
+
+fn card_status(alert_json: &serde_json::Value, theme: &Theme, locale: &str) -> CardStatus {
+    let mut status = CardStatus {
+        title: serde_json::Value::String("".to_string()),
+        color: "".to_string(),
+        style: "default",
+        subtitle: serde_json::Value::String("".to_string()),
+        icon: ACTIVITY_IMAGE.to_string(),
+    };
+
+    // Set status of card
+    if alert_json["status"].is_string() {
+        let name = alert_json["status"].as_str().unwrap_or_default();
+        let (color, style) = match name {
+            "OPEN" => (RED, "attention"),
+            "CLOSED" => (GREEN, "good"),
+            "INFORMATIONAL" => (YELLOW, "warning"),
+            "TRACKING" => (OTHER, "accent"),
+            "CANCELLED" => (GRAY, "default"),
+            _ => (OTHER, "accent"),
+        };
+        // Statuses without a title in the catalog are shown as they are
+        status.title = match catalog::title(locale, name) {
+            Some(title) => json!(title),
+            None => alert_json["status"].clone(),
+        };
+        status.color = color.to_string();
+        status.style = style;
+
+        // Closed alerts show when they were resolved, every other status when it was created
+        if alert_json["status"] == "CLOSED" {
+            if alert_json["updated"].is_string() {
+                status.subtitle = alert_json["resolved"].clone()
+            };
+        } else if alert_json["created"].is_string() {
+            status.subtitle = alert_json["created"].clone()
+        };
+
+        if let Some(themed) = theme.status(name) {
+            if let Some(title) = &themed.title {
+                status.title = json!(title);
+            }
+            if let Some(color) = &themed.color {
+                status.color = theme::normalize_color(color);
+            }
+            if let Some(icon) = &themed.icon {
+                status.icon = icon.clone();
+            }
+        }
+
+        // Acknowledged alerts are still open, only the title tells them apart
+        if name == "OPEN" && alert_json["acknowledgedUntil"].is_string() {
+            if let Some(title) = catalog::title(locale, "ACKNOWLEDGED") {
+                status.title = json!(title);
+            }
+        }
+
+        // Severity colors, except for resolutions which keep their own color
+        if name != "CLOSED" {
+            if let Some(color) =
+                theme.severity_color(alert_json["eventTypeName"].as_str().unwrap_or_default())
+            {
+                status.color = color;
+            }
+        }
+    };
+
+    status
+}
+
+// Return the activity title and summary based on eventTypeName
+fn card_description(
+    alert_json: &serde_json::Value,
+    title: &serde_json::Value,
+    locale: &str,
+) -> (serde_json::Value, String) {
+    if alert_json["eventTypeName"].is_string() {
+        match catalog::describe(
+            locale,
+            alert_json["eventTypeName"]
+                .as_str()
+                .expect("Logically, we should not have hit this error"),
+        ) {
+            Some(string) => (json!(string), format!("[{}]: {}", title, string)),
+            None => (
+                alert_json["eventTypeName"].clone(),
+                format!(
+                    "[{}]: {}",
+                    title,
+                    catalog::text(locale, "unknown_event", &[])
+                ),
+            ),
+        }
+    } else {
+        // Every event should have an eventTypeName, but return a error in the response if an event does not
+        (
+            json!(catalog::text(locale, "missing_event", &[])),
+            catalog::text(locale, "missing_event_summary", &[]),
+        )
+    }
+}
+
+fn fact(locale: &str, field: &str, value: &serde_json::Value) -> FactEntry {
+    FactEntry {
+        name: catalog::label(locale, field),
+        value: value.to_string(),
+        text: value
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| value.to_string()),
+    }
+}
+
+// The replica set, cluster or project the alert is about, whichever is most specific
+fn scope_fact(alert_json: &serde_json::Value, locale: &str) -> Option<FactEntry> {
+    ["replicaSetName", "clusterName", "groupId"]
+        .iter()
+        .find(|field| alert_json[**field].is_string())
+        .map(|field| fact(locale, field, &alert_json[*field]))
+}
+
+fn card_facts(alert_json: &serde_json::Value, locale: &str) -> Vec<FactEntry> {
+    // Create facts array and push to card
+    let mut facts_vec: Vec<FactEntry> = scope_fact(alert_json, locale).into_iter().collect();
+    let fields = [
+        ("hostnameAndPort", &alert_json["hostnameAndPort"]),
+        ("sourceTypeName", &alert_json["sourceTypeName"]),
+        ("metricName", &alert_json["metricName"]),
+        ("metricValue", &alert_json["currentValue"]["number"]),
+        ("metricUnit", &alert_json["currentValue"]["units"]),
+        ("typeName", &alert_json["typeName"]),
+        (
+            "acknowledgingUsername",
+            &alert_json["acknowledgingUsername"],
+        ),
+        ("acknowledgedUntil", &alert_json["acknowledgedUntil"]),
+        (
+            "acknowledgementComment",
+            &alert_json["acknowledgementComment"],
+        ),
+    ];
+    for (field, value) in fields.iter() {
+        if value.is_string() {
+            facts_vec.push(fact(locale, field, value));
+        }
+    }
+
+    facts_vec
+}
+
+// Everything a card is built from, whether it describes a single alert or a digest
+struct CardContent {
+    status: CardStatus,
+    activity_title: serde_json::Value,
+    summary: String,
+    facts: Vec<FactEntry>,
+    actions: Vec<Action>,
+}
+
+impl CardContent {
+    fn with_actions(self, actions: Vec<Action>) -> CardContent {
+        CardContent { actions, ..self }
+    }
+}
+
+fn card_content(alert_json: &serde_json::Value, theme: &Theme, locale: &str) -> CardContent {
+    let status = card_status(alert_json, theme, locale);
+    let (activity_title, summary) = card_description(alert_json, &status.title, locale);
+    CardContent {
+        status,
+        activity_title,
+        summary,
+        facts: card_facts(alert_json, locale),
+        actions: Vec::new(),
+    }
+}
+
+// Values derived from an alert, handed to user templates next to the alert itself
+pub fn card_context(
+    alert_json: &serde_json::Value,
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> serde_json::Value {
+    let content = card_content(alert_json, theme, locale);
+    json!({
+        "title": content.status.title,
+        "color": content.status.color,
+        "style": content.status.style,
+        "subtitle": content.status.subtitle,
+        "description": content.activity_title,
+        "summary": content.summary,
+        "image": content.status.icon,
+        "facts": content.facts,
+        "actions": actions,
+    })
+}
+
+// Hex color used for cards of the given alert status
+pub fn status_color(status: &str, theme: &Theme) -> String {
+    card_status(&json!({ "status": status }), theme, catalog::DEFAULT_LOCALE).color
+}
+
+// Human readable description of an eventTypeName
+pub fn describe_event(event_type: &str, locale: &str) -> String {
+    catalog::describe(locale, event_type)
+        .unwrap_or_else(|| catalog::text(locale, "unknown_event", &[]))
 }
 
 // Accept alert json and return microsoft teams card
 pub fn create_card(
     alert_json: serde_json::Value,
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    message_card(card_content(&alert_json, theme, locale).with_actions(actions))
+}
+
+// Accept alert json and return an adaptive card wrapped for a teams workflows webhook
+pub fn create_adaptive_card(
+    alert_json: serde_json::Value,
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(adaptive_card(
+        card_content(&alert_json, theme, locale).with_actions(actions),
+    ))
+}
+
+// Accept alert json and return a slack block kit message with a colored attachment
+pub fn create_slack_message(
+    alert_json: serde_json::Value,
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(slack_message(
+        card_content(&alert_json, theme, locale).with_actions(actions),
+    ))
+}
+
+fn message_card(
+    content: CardContent,
 ) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
     let mut card_body = json!({
         "@type": "MessageCard",
@@ -21,276 +300,490 @@ pub fn create_card(
             {
                 "activityTitle": "",
                 "activitySubtitle": "",
-                "activityImage": "https://company-30077.frontify.com/api/screen/download/eyJpZCI6MzQwMzI2NCwidmVyc2lvbiI6IjIwMTktMDgtMDIgMTk6Mjg6MDYifQ:frontify:DkJTntON9g0YByA8Q_M4vJX_XxO7je1rn7PJN6RJ_TI/?download&title_as_filename&track",
+                "activityImage": "",
                 "facts": {}
             }
         ]
     });
 
-    let green: &str = "12924F";
-    let yellow: &str = "12924F";
-    let red: &str = "D7000C";
-    let other: &str = "0078D7";
+    let status = content.status;
+    card_body["title"] = status.title.clone();
+    card_body["themeColor"] = serde_json::to_value(status.color)?;
+    card_body["sections"][0]["activitySubtitle"] = status.subtitle;
+    card_body["sections"][0]["activityImage"] = json!(status.icon);
 
-    // Set status of card
-    if alert_json["status"].is_string() {
-        match alert_json["status"].as_str() {
-            Some("OPEN") => {
-                card_body["title"] = serde_json::to_value("New Alert Triggered")?;
-                card_body["themeColor"] = serde_json::to_value(red)?;
-                if alert_json["created"].is_string() {
-                    card_body["sections"][0]["activitySubtitle"] = alert_json["created"].clone()
-                };
-            }
-            Some("CLOSED") => {
-                card_body["title"] = serde_json::to_value("Alert Closed")?;
-                card_body["themeColor"] = serde_json::to_value(green)?;
-                if alert_json["updated"].is_string() {
-                    card_body["sections"][0]["activitySubtitle"] = alert_json["resolved"].clone()
-                };
-            }
-            Some("INFORMATIONAL") => {
-                card_body["title"] = serde_json::to_value("Informational Alert")?;
-                card_body["themeColor"] = serde_json::to_value(yellow)?;
-                if alert_json["created"].is_string() {
-                    card_body["sections"][0]["activitySubtitle"] = alert_json["created"].clone()
-                };
-            }
-            _ => {
-                card_body["title"] = serde_json::to_value(alert_json["status"].clone())?;
-                card_body["themeColor"] = serde_json::to_value(other)?;
-                if alert_json["created"].is_string() {
-                    card_body["sections"][0]["activitySubtitle"] = alert_json["created"].clone()
-                };
-            }
-        }
-    };
+    card_body["sections"][0]["activityTitle"] = content.activity_title;
+    card_body["summary"] = serde_json::to_value(content.summary)?;
 
-    // Set title and summary based on eventTypeName
-    if alert_json["eventTypeName"].is_string() {
-        match get_message_string(
-            alert_json["eventTypeName"]
-                .as_str()
-                .expect("Logically, we should not have hit this error"),
-        ) {
-            Some(string) => {
-                card_body["sections"][0]["activityTitle"] = serde_json::to_value(string)?;
-                let summary = format!("[{}]: {}", card_body["title"], string);
-                card_body["summary"] = serde_json::to_value(summary)?;
-            }
-            None => {
-                card_body["sections"][0]["activityTitle"] = alert_json["eventTypeName"].clone();
-                let summary = format!("[{}]: {}", card_body["title"], "Unknown event type");
-                card_body["summary"] = serde_json::to_value(summary)?;
+    let facts = json!(content.facts);
+    card_body["sections"][0]["facts"] = facts;
+
+    if !content.actions.is_empty() {
+        let actions: Vec<serde_json::Value> = content
+            .actions
+            .into_iter()
+            .map(|action| {
+                json!({
+                    "@type": "OpenUri",
+                    "name": action.title,
+                    "targets": [{ "os": "default", "uri": action.url }]
+                })
+            })
+            .collect();
+        card_body["potentialAction"] = json!(actions);
+    }
+    Ok(card_body)
+}
+
+fn adaptive_card(content: CardContent) -> serde_json::Value {
+    let status = content.status;
+    let actions: Vec<serde_json::Value> = content
+        .actions
+        .into_iter()
+        .map(|action| json!({"type": "Action.OpenUrl", "title": action.title, "url": action.url}))
+        .collect();
+    let facts: Vec<serde_json::Value> = content
+        .facts
+        .into_iter()
+        .map(|fact| json!({"title": fact.name, "value": fact.text}))
+        .collect();
+
+    let mut card = json!({
+        "type": "message",
+        "summary": content.summary,
+        "attachments": [
+            {
+                "contentType": "application/vnd.microsoft.card.adaptive",
+                "contentUrl": null,
+                "content": {
+                    "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                    "type": "AdaptiveCard",
+                    "version": "1.4",
+                    "msteams": { "width": "Full" },
+                    "body": [
+                        {
+                            "type": "Container",
+                            "style": status.style,
+                            "bleed": true,
+                            "items": [
+                                {
+                                    "type": "TextBlock",
+                                    "text": status.title,
+                                    "weight": "Bolder",
+                                    "size": "Medium",
+                                    "wrap": true
+                                }
+                            ]
+                        },
+                        {
+                            "type": "ColumnSet",
+                            "columns": [
+                                {
+                                    "type": "Column",
+                                    "width": "auto",
+                                    "items": [
+                                        {
+                                            "type": "Image",
+                                            "url": status.icon,
+                                            "size": "Small"
+                                        }
+                                    ]
+                                },
+                                {
+                                    "type": "Column",
+                                    "width": "stretch",
+                                    "items": [
+                                        {
+                                            "type": "TextBlock",
+                                            "text": content.activity_title,
+                                            "weight": "Bolder",
+                                            "wrap": true
+                                        },
+                                        {
+                                            "type": "TextBlock",
+                                            "text": status.subtitle,
+                                            "isSubtle": true,
+                                            "spacing": "None",
+                                            "wrap": true
+                                        }
+                                    ]
+                                }
+                            ]
+                        },
+                        {
+                            "type": "FactSet",
+                            "facts": facts
+                        }
+                    ]
+                }
             }
+        ]
+    });
+    if !actions.is_empty() {
+        card["attachments"][0]["content"]["actions"] = json!(actions);
+    }
+    card
+}
+
+fn slack_message(content: CardContent) -> serde_json::Value {
+    let status = content.status;
+    let title = match status.title.as_str() {
+        Some(title) => title.to_string(),
+        None => status.title.to_string(),
+    };
+    let mut description = format!("*{}*", content.activity_title.as_str().unwrap_or_default());
+    if let Some(subtitle) = status.subtitle.as_str() {
+        if !subtitle.is_empty() {
+            description = format!("{}\n{}", description, subtitle);
         }
-    } else {
-        // Every event should have an eventTypeName, but return a error in the response if an event does not
-        card_body["sections"][0]["activityTitle"] =
-            serde_json::to_value("Missing eventTypeName".to_string())?;
-        card_body["summary"] = serde_json::to_value("Error, unknown eventTypeName".to_string())?;
     }
 
-    let mut facts_vec: Vec<FactEntry> = Vec::new();
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": { "type": "plain_text", "text": title }
+        }),
+        json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": description }
+        }),
+    ];
 
-    // Create facts array and push to card
-    if alert_json["replicaSetName"].is_string() {
-        let fact = FactEntry {
-            name: "Replicaset".to_string(),
-            value: alert_json["replicaSetName"].to_string(),
-        };
-        facts_vec.push(fact);
-    } else if alert_json["clusterName"].is_string() {
-        let fact = FactEntry {
-            name: "Cluster Name".to_string(),
-            value: alert_json["clusterName"].to_string(),
-        };
-        facts_vec.push(fact);
-    } else if alert_json["groupId"].is_string() {
-        let fact = FactEntry {
-            name: "Group".to_string(),
-            value: alert_json["groupId"].to_string(),
-        };
-        facts_vec.push(fact);
+    // Slack allows at most ten fields per section
+    let fields: Vec<serde_json::Value> = content
+        .facts
+        .into_iter()
+        .map(|fact| json!({"type": "mrkdwn", "text": format!("*{}*\n{}", fact.name, fact.text)}))
+        .collect();
+    for fields in fields.chunks(10) {
+        blocks.push(json!({
+            "type": "section",
+            "fields": fields
+        }));
     }
-    if alert_json["hostnameAndPort"].is_string() {
-        let fact = FactEntry {
-            name: "Server".to_string(),
-            value: alert_json["hostnameAndPort"].to_string(),
-        };
-        facts_vec.push(fact);
+
+    if !content.actions.is_empty() {
+        let buttons: Vec<serde_json::Value> = content
+            .actions
+            .into_iter()
+            .map(|action| {
+                json!({
+                    "type": "button",
+                    "text": { "type": "plain_text", "text": action.title },
+                    "url": action.url
+                })
+            })
+            .collect();
+        blocks.push(json!({
+            "type": "actions",
+            "elements": buttons
+        }));
     }
-    if alert_json["sourceTypeName"].is_string() {
-        let fact = FactEntry {
-            name: "Source Type".to_string(),
-            value: alert_json["sourceTypeName"].to_string(),
-        };
-        facts_vec.push(fact);
+
+    json!({
+        "text": content.summary,
+        "attachments": [
+            {
+                "color": format!("#{}", status.color),
+                "blocks": blocks
+            }
+        ]
+    })
+}
+
+// What happened to one alert id during a digest window
+struct Occurrence {
+    id: String,
+    name: String,
+    status: String,
+    opened: Option<String>,
+    closed: Option<String>,
+}
+
+impl Occurrence {
+    // Opened and closed again before the digest went out
+    fn flapped(&self) -> bool {
+        self.opened.is_some() && self.closed.is_some()
     }
-    if alert_json["metricName"].is_string() {
-        let fact = FactEntry {
-            name: "Metric Name".to_string(),
-            value: alert_json["metricName"].to_string(),
+
+    fn describe(&self, locale: &str) -> String {
+        let at = |time: &Option<String>| {
+            time.clone()
+                .unwrap_or_else(|| catalog::text(locale, "unknown_time", &[]))
         };
-        facts_vec.push(fact);
+        if self.flapped() {
+            catalog::text(
+                locale,
+                "flapped",
+                &[("opened", at(&self.opened)), ("closed", at(&self.closed))],
+            )
+        } else if self.status == "OPEN" {
+            catalog::text(locale, "open_since", &[("opened", at(&self.opened))])
+        } else if self.status == "CLOSED" {
+            catalog::text(locale, "closed_at", &[("closed", at(&self.closed))])
+        } else {
+            self.status.clone()
+        }
     }
-    if alert_json["currentValue"]["number"].is_string() {
-        let fact = FactEntry {
-            name: "Metric Value".to_string(),
-            value: alert_json["currentValue"]["number"].to_string(),
+}
+
+// Fold the buffered alerts into one occurrence per alert id, in the order they first arrived
+fn occurrences(alerts: &[serde_json::Value]) -> Vec<Occurrence> {
+    let mut occurrences: Vec<Occurrence> = Vec::new();
+    for alert in alerts {
+        let id = alert["id"].as_str().unwrap_or_default().to_string();
+        let index = match occurrences
+            .iter()
+            .position(|occurrence| occurrence.id == id)
+        {
+            Some(index) => index,
+            None => {
+                let name = alert["hostnameAndPort"].as_str().unwrap_or(&id).to_string();
+                occurrences.push(Occurrence {
+                    id,
+                    name,
+                    status: String::new(),
+                    opened: None,
+                    closed: None,
+                });
+                occurrences.len() - 1
+            }
         };
-        facts_vec.push(fact);
+        let occurrence = &mut occurrences[index];
+        occurrence.status = alert["status"].as_str().unwrap_or_default().to_string();
+        match occurrence.status.as_str() {
+            "OPEN" => occurrence.opened = alert["created"].as_str().map(String::from),
+            "CLOSED" => {
+                occurrence.closed = alert["resolved"]
+                    .as_str()
+                    .or_else(|| alert["updated"].as_str())
+                    .map(String::from)
+            }
+            _ => {}
+        }
     }
-    if alert_json["currentValue"]["units"].is_string() {
-        let fact = FactEntry {
-            name: "Metric Unit".to_string(),
-            value: alert_json["currentValue"]["units"].to_string(),
-        };
-        facts_vec.push(fact);
+    occurrences
+}
+
+// Ids of the alerts that opened and closed inside the window
+pub fn flapped(alerts: &[serde_json::Value]) -> Vec<String> {
+    occurrences(alerts)
+        .into_iter()
+        .filter(|occurrence| occurrence.flapped())
+        .map(|occurrence| occurrence.id)
+        .collect()
+}
+
+fn digest_content(alerts: &[serde_json::Value], theme: &Theme, locale: &str) -> CardContent {
+    let first = alerts.first().cloned().unwrap_or_default();
+    let occurrences = occurrences(alerts);
+    let flapped = occurrences.iter().filter(|o| o.flapped()).count();
+    let open = occurrences
+        .iter()
+        .filter(|o| !o.flapped() && o.status == "OPEN")
+        .count();
+    let closed = occurrences
+        .iter()
+        .filter(|o| !o.flapped() && o.status == "CLOSED")
+        .count();
+
+    // Anything still open outweighs flapping, which outweighs alerts that are all closed.
+    // Flapping is shown like an informational alert.
+    let shown = if open > 0 {
+        "OPEN"
+    } else if flapped > 0 {
+        "INFORMATIONAL"
+    } else {
+        "CLOSED"
+    };
+    let themed = card_status(
+        &json!({ "status": shown, "eventTypeName": first["eventTypeName"] }),
+        theme,
+        locale,
+    );
+
+    let times = alerts
+        .iter()
+        .flat_map(|alert| vec![alert["created"].as_str(), alert["updated"].as_str()])
+        .flatten();
+    let (since, until) = (times.clone().min(), times.max());
+    let count = ("count", alerts.len().to_string());
+    let subtitle = match (since, until) {
+        (Some(since), Some(until)) => catalog::text(
+            locale,
+            "digest_window",
+            &[
+                count,
+                ("since", since.to_string()),
+                ("until", until.to_string()),
+            ],
+        ),
+        _ => catalog::text(locale, "digest_alerts", &[count]),
+    };
+
+    let status = CardStatus {
+        title: json!(catalog::title(locale, "DIGEST").unwrap_or_default()),
+        color: themed.color,
+        style: themed.style,
+        subtitle: json!(subtitle),
+        icon: themed.icon,
+    };
+    let (activity_title, summary) = card_description(&first, &status.title, locale);
+    let counts = catalog::text(
+        locale,
+        "digest_counts",
+        &[
+            ("open", open.to_string()),
+            ("closed", closed.to_string()),
+            ("flapped", flapped.to_string()),
+        ],
+    );
+
+    // Keep the fact naming the cluster the digest is about, then list every alert
+    let mut facts: Vec<FactEntry> = scope_fact(&first, locale).into_iter().collect();
+    facts.push(FactEntry::new(
+        catalog::label(locale, "occurrences"),
+        counts.clone(),
+    ));
+    let total = occurrences.len();
+    for occurrence in occurrences.into_iter().take(DIGEST_MAX_OCCURRENCES) {
+        facts.push(FactEntry::new(
+            occurrence.name.clone(),
+            occurrence.describe(locale),
+        ));
     }
-    if alert_json["typeName"].is_string() {
-        let fact = FactEntry {
-            name: "Type".to_string(),
-            value: alert_json["typeName"].to_string(),
-        };
-        facts_vec.push(fact);
+    if total > DIGEST_MAX_OCCURRENCES {
+        facts.push(FactEntry::new(
+            catalog::label(locale, "more"),
+            catalog::text(
+                locale,
+                "more_alerts",
+                &[("count", (total - DIGEST_MAX_OCCURRENCES).to_string())],
+            ),
+        ));
     }
 
-    let facts = json!(facts_vec);
-    card_body["sections"][0]["facts"] = facts;
-    Ok(card_body)
+    CardContent {
+        status,
+        activity_title,
+        summary: format!("{} ({})", summary, counts),
+        facts,
+        actions: Vec::new(),
+    }
+}
+
+// Accept the alerts buffered for one digest and return a single microsoft teams card
+pub fn create_digest_card(
+    alerts: &[serde_json::Value],
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    message_card(digest_content(alerts, theme, locale).with_actions(actions))
 }
 
-pub fn get_message_string(alert_type: &str) -> Option<&str> {
-    match alert_type {
-        "AUTOMATION_AGENT_DOWN" => Some("Automation is down"),
-        "AUTOMATION_AGENT_UP" => Some("Automation is up"),
-        "BACKUP_AGENT_CONF_CALL_FAILURE" => Some("Backup has too many conf call failures"),
-        "BACKUP_AGENT_DOWN" => Some("Backup is down"),
-        "BACKUP_AGENT_UP" => Some("Backup is up"),
-        "BACKUP_AGENT_VERSION_BEHIND" => Some("Backup does not have the latest version"),
-        "BACKUP_AGENT_VERSION_CURRENT" => Some("Backup has the latest version"),
-        "BLOCKSTORE_JOB_TOO_MANY_RETRIES" => Some("Blockstore jobs have reached a high number of retries"),
-        "MONITORING_AGENT_DOWN" => Some("Monitoring is down"),
-        "MONITORING_AGENT_UP" => Some("Monitoring is up"),
-        "MONITORING_AGENT_VERSION_BEHIND" => Some("Monitoring does not have the latest version"),
-        "MONITORING_AGENT_VERSION_CURRENT" => Some("Monitoring has the latest version"),
-        "AUTOMATION_CONFIG_PUBLISHED_AUDIT" => Some("Deployment configuration published"),
-        "BAD_CLUSTERSHOTS" => Some("Backup has possibly inconsistent cluster snapshots"),
-        "CLUSTER_BLACKLIST_UPDATED_AUDIT" => Some("Excluded namespaces were modified for cluster"),
-        "CLUSTER_CHECKKPOINT_UPDATED_AUDIT" => Some("Checkpoint interval updated for cluster"),
-        "CLUSTER_CREDENTIAL_UPDATED_AUDIT" => Some("Backup authentication credentials updated for cluster"),
-        "CLUSTER_SNAPSHOT_SCHEDULE_UPDATED_AUDIT" => Some("Snapshot schedule updated for cluster"),
-        "CLUSTER_STATE_CHANGED_AUDIT" => Some("Cluster backup state is now"),
-        "CLUSTER_STORAGE_ENGINE_UPDATED_AUDIT" => Some("Cluster storage engine has been updated"),
-        "CLUSTERSHOT_DELETED_AUDIT" => Some("Cluster snapshot has been deleted"),
-        "CLUSTERSHOT_EXPIRY_UPDATED_AUDIT" => Some("Clustershot expiry has been updated"),
-        "CONSISTENT_BACKUP_CONFIGURATION" => Some("Backup configuration is consistent"),
-        "GOOD_CLUSTERSHOT" => Some("Backup has a good clustershot"),
-        "INCONSISTENT_BACKUP_CONFIGURATION" => Some("Inconsistent backup configuration has been detected"),
-        "INITIAL_SYNC_FINISHED_AUDIT" => Some("Backup initial sync finished"),
-        "INITIAL_SYNC_STARTED_AUDIT" => Some("Backup initial sync started"),
-        "OPLOG_BEHIND" => Some("Backup oplog is behind"),
-        "OPLOG_CURRENT" => Some("Backup oplog is current"),
-        "RESTORE_REQUESTED_AUDIT" => Some("A restore has been requested"),
-        "RESYNC_PERFORMED" => Some("Backup has been resynced"),
-        "RESYNC_REQUIRED" => Some("Backup requires a resync"),
-        "RS_BLACKLIST_UPDATED_AUDIT" => Some("Excluded namespaces were modified for replica set"),
-        "RS_CREDENTIAL_UPDATED_AUDIT" => Some("Backup authentication credentials updated for replica set"),
-        "RS_ROTATE_MASTER_KEY_AUDIT" => Some("A master key rotation has been requested for a replica set"),
-        "RS_SNAPSHOT_SCHEDULE_UPDATED_AUDIT" => Some("Snapshot schedule updated for replica set"),
-        "RS_STATE_CHANGED_AUDIT" => Some("Replica set backup state is now"),
-        "RS_STORAGE_ENGINE_UPDATED_AUDIT" => Some("Replica set storage engine has been updated"),
-        "SNAPSHOT_DELETED_AUDIT" => Some("Snapshot has been deleted"),
-        "SNAPSHOT_EXPIRY_UPDATED_AUDIT" => Some("Snapshot expiry has been updated"),
-        "SYNC_PENDING_AUDIT" => Some("Backup sync is pending"),
-        "SYNC_REQUIRED_AUDIT" => Some("Backup sync has been initiated"),
-        "BI_CONNECTOR_DOWN" => Some("BI Connector is down"),
-        "BI_CONNECTOR_UP" => Some("BI Connector is up Project"),
-        "CLUSTER_MONGOS_IS_MISSING" => Some("Cluster is missing an active mongos"),
-        "CLUSTER_MONGOS_IS_PRESENT" => Some("Cluster has an active mongos"),
-        "SHARD_ADDED" => Some("Shard added"),
-        "SHARD_REMOVED" => Some("Shard removed"),
-        "DATA_EXPLORER" => Some("User performed a Data Explorer read-only operation"),
-        "DATA_EXPLORER_CRUD" => Some("User performed a Data Explorer CRUD operation"),
-        "ADD_HOST_AUDIT" => Some("Host added"),
-        "ADD_HOST_TO_REPLICA_SET_AUDIT" => Some("Host added to replica set"),
-        "ATTEMPT_KILLOP_AUDIT" => Some("Attempted to kill operation"),
-        "ATTEMPT_KILLSESSION_AUDIT" => Some("Attempted to kill session"),
-        "DB_PROFILER_DISABLE_AUDIT" => Some("Database profiling disabled"),
-        "DB_PROFILER_ENABLE_AUDIT" => Some("Database profiling enabled"),
-        "DELETE_HOST_AUDIT" => Some("Host removed"),
-        "DISABLE_HOST_AUDIT" => Some("Host disabled"),
-        "HIDE_AND_DISABLE_HOST_AUDIT" => Some("Host disabled and hidden"),
-        "HIDE_HOST_AUDIT" => Some("Host hidden"),
-        "HOST_DOWN" => Some("Host is down"),
-        "HOST_DOWNGRADED" => Some("Host has been downgraded"),
-        "HOST_IP_CHANGED_AUDIT" => Some("Host IP address changed"),
-        "HOST_NOW_PRIMARY" => Some("Host is now primary"),
-        "HOST_NOW_SECONDARY" => Some("Host is now secondary"),
-        "HOST_NOW_STANDALONE" => Some("Host is now a standalone"),
-        "HOST_RECOVERED" => Some("Host has recovered"),
-        "HOST_RECOVERING" => Some("Host is recovering"),
-        "HOST_RESTARTED" => Some("Host has restarted"),
-        "HOST_ROLLBACK" => Some("Host experienced a rollback"),
-        "HOST_SSL_CERTIFICATE_CURRENT" => Some("Host’s SSL certificate is current"),
-        "HOST_SSL_CERTIFICATE_STALE" => Some("Host’s SSL certificate will expire within 30 days"),
-        "HOST_UP" => Some("Host is up"),
-        "HOST_UPGRADED" => Some("Host has been upgraded"),
-        "INSIDE_METRIC_THRESHOLD" => Some("Inside metric threshold"),
-        "NEW_HOST" => Some("Host is new"),
-        "OUTSIDE_METRIC_THRESHOLD" => Some("Outside metric threshold"),
-        "PAUSE_HOST_AUDIT" => Some("Host paused"),
-        "REMOVE_HOST_FROM_REPLICA_SET_AUDIT" => Some("Host removed from replica set"),
-        "RESUME_HOST_AUDIT" => Some("Host resumed"),
-        "UNDELETE_HOST_AUDIT" => Some("Host undeleted"),
-        "VERSION_BEHIND" => Some("Host does not have the latest version"),
-        "VERSION_CHANGED" => Some("Host version changed"),
-        "VERSION_CURRENT" => Some("Host has the latest version Project"),
-        "ALL_ORG_USERS_HAVE_MFA" => Some("Organization users have two-factor authentication enabled"),
-        "ORG_API_KEY_ADDED" => Some("API key has been added"),
-        "ORG_API_KEY_DELETED" => Some("API key has been deleted"),
-        "ORG_EMPLOYEE_ACCESS_RESTRICTED" => Some("MongoDB Production Support Employees restricted from accessing Atlas backend infrastructure for any Atlas cluster in this organization (You may grant a 24 hour bypass to the access restriction at the Atlas cluster level),"),
-        "ORG_EMPLOYEE_ACCESS_UNRESTRICTED" => Some("MongoDB Production Support Employees unrestricted from accessing Atlas backend infrastructure for any Atlas cluster in this organization"),
-        "ORG_PUBLIC_API_WHITELIST_NOT_REQUIRED" => Some("IP Whitelist for Public API Not Required"),
-        "ORG_PUBLIC_API_WHITELIST_REQUIRED" => Some("Require IP Whitelist for Public API Enabled"),
-        "ORG_RENAMED" => Some("Organization has been renamed"),
-        "ORG_TWO_FACTOR_AUTH_OPTIONAL" => Some("Two-factor Authentication Optional"),
-        "ORG_TWO_FACTOR_AUTH_REQUIRED" => Some("Two-factor Authentication Required"),
-        "ORG_USERS_WITHOUT_MFA" => Some("Organization users do not have two-factor authentication enabled"),
-        "ALL_USERS_HAVE_MULTIFACTOR_AUTH" => Some("Users have two-factor authentication enabled"),
-        "USERS_WITHOUT_MULTIFACTOR_AUTH" => Some("Users do not have two-factor authentication enabled"),
-        "CONFIGURATION_CHANGED" => Some("Replica set has an updated configuration"),
-        "ENOUGH_HEALTHY_MEMBERS" => Some("Replica set has enough healthy members"),
-        "MEMBER_ADDED" => Some("Replica set member added"),
-        "MEMBER_REMOVED" => Some("Replica set member removed"),
-        "MULTIPLE_PRIMARIES" => Some("Replica set elected multiple primaries"),
-        "NO_PRIMARY" => Some("Replica set has no primary"),
-        "ONE_PRIMARY" => Some("Replica set elected one primary"),
-        "PRIMARY_ELECTED" => Some("Replica set elected a new primary"),
-        "TOO_FEW_HEALTHY_MEMBERS" => Some("Replica set has too few healthy members"),
-        "TOO_MANY_ELECTIONS" => Some("Replica set has too many election events"),
-        "TOO_MANY_UNHEALTHY_MEMBERS" => Some("Replica set has too many unhealthy members"),
-        "TEAM_ADDED_TO_GROUP" => Some("Team added to project"),
-        "TEAM_CREATED" => Some("Team created"),
-        "TEAM_DELETED" => Some("Team deleted"),
-        "TEAM_NAME_CHANGED" => Some("Team name changed"),
-        "TEAM_REMOVED_FROM_GROUP" => Some("Team removed from project"),
-        "TEAM_ROLES_MODIFIED" => Some("Team roles modified in project"),
-        "TEAM_UPDATED" => Some("Team updated"),
-        "USER_ADDED_TO_TEAM" => Some("User added to team"),
-        "INVITED_TO_GROUP" => Some("User was invited to project"),
-        "INVITED_TO_ORG" => Some("User was invited to organization"),
-        "JOIN_GROUP_REQUEST_APPROVED_AUDIT" => Some("Request to join project was approved"),
-        "JOIN_GROUP_REQUEST_DENIED_AUDIT" => Some("Request to join project was denied"),
-        "JOINED_GROUP" => Some("User joined the project"),
-        "JOINED_ORG" => Some("User joined the organization"),
-        "JOINED_TEAM" => Some("User joined the team"),
-        "REMOVED_FROM_GROUP" => Some("User left the project"),
-        "REMOVED_FROM_ORG" => Some("User left the organization"),
-        "REMOVED_FROM_TEAM" => Some("User left the team"),
-        "REQUESTED_TO_JOIN_GROUP" => Some("User requested to join project"),
-        "USER_ROLES_CHANGED_AUDIT" => Some("User had their role changed"),
-        _ => None
+pub fn create_digest_adaptive_card(
+    alerts: &[serde_json::Value],
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(adaptive_card(
+        digest_content(alerts, theme, locale).with_actions(actions),
+    ))
+}
+
+pub fn create_digest_slack_message(
+    alerts: &[serde_json::Value],
+    theme: &Theme,
+    locale: &str,
+    actions: Vec<Action>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(slack_message(
+        digest_content(alerts, theme, locale).with_actions(actions),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(id: &str, status: &str, time: &str) -> serde_json::Value {
+        let mut alert = json!({
+            "id": id,
+            "status": status,
+            "hostnameAndPort": format!("{}.example.com:27017", id),
+        });
+        match status {
+            "OPEN" => alert["created"] = json!(time),
+            _ => alert["resolved"] = json!(time),
+        }
+        alert
+    }
+
+    #[test]
+    fn occurrences_fold_alerts_by_id_in_arrival_order() {
+        let occurrences = occurrences(&[
+            alert("b", "OPEN", "09:00"),
+            alert("a", "OPEN", "09:01"),
+            alert("b", "CLOSED", "09:05"),
+        ]);
+        let ids: Vec<&str> = occurrences.iter().map(|o| o.id.as_str()).collect();
+        assert_eq!(ids, vec!["b", "a"]);
+        assert_eq!(occurrences[0].name, "b.example.com:27017");
+        assert_eq!(occurrences[0].status, "CLOSED");
+        assert_eq!(occurrences[0].opened.as_deref(), Some("09:00"));
+        assert_eq!(occurrences[0].closed.as_deref(), Some("09:05"));
+        assert_eq!(occurrences[1].status, "OPEN");
+        assert_eq!(occurrences[1].closed, None);
+    }
+
+    #[test]
+    fn closing_falls_back_to_updated_and_names_to_the_id() {
+        let closed = json!({"id": "a", "status": "CLOSED", "updated": "09:07"});
+        let occurrences = occurrences(&[closed]);
+        assert_eq!(occurrences[0].name, "a");
+        assert_eq!(occurrences[0].opened, None);
+        assert_eq!(occurrences[0].closed.as_deref(), Some("09:07"));
+    }
+
+    #[test]
+    fn flapped_lists_alerts_that_opened_and_closed() {
+        let alerts = [
+            alert("a", "OPEN", "09:00"),
+            alert("b", "OPEN", "09:01"),
+            alert("a", "CLOSED", "09:02"),
+            alert("c", "CLOSED", "09:03"),
+        ];
+        assert_eq!(flapped(&alerts), vec!["a"]);
+
+        // Closing before opening, as when posts arrive out of order, still counts
+        let reversed = [alert("d", "CLOSED", "09:02"), alert("d", "OPEN", "09:00")];
+        assert_eq!(flapped(&reversed), vec!["d"]);
+        assert!(flapped(&[]).is_empty());
+    }
+
+    #[test]
+    fn occurrences_describe_what_happened() {
+        let occurrences = occurrences(&[
+            alert("a", "OPEN", "09:00"),
+            alert("a", "CLOSED", "09:02"),
+            alert("b", "OPEN", "09:01"),
+            alert("c", "CLOSED", "09:03"),
+            json!({"id": "d", "status": "TRACKING"}),
+        ]);
+        let described: Vec<String> = occurrences
+            .iter()
+            .map(|o| o.describe(catalog::DEFAULT_LOCALE))
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                "Flapped, opened 09:00 and closed 09:02",
+                "Open since 09:01",
+                "Closed at 09:03",
+                "TRACKING",
+            ]
+        );
     }
 }